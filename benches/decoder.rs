@@ -0,0 +1,34 @@
+//! Throughput of `Decoder::feed`, run with `cargo bench`.
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use natpmp::{Decoder, Natpmp, Protocol};
+
+const ROUNDS: u32 = 1_000_000;
+
+fn main() {
+    let client = "192.168.0.10:40000".parse().unwrap();
+    let gateway = "192.168.0.1:5351".parse().unwrap();
+    let request = Natpmp::build_mapping_request(Protocol::TCP, 8080, 8080, 3600).unwrap();
+    let response = [
+        0u8, 130, 0, 0, 0, 0, 0, 1, 0x1f, 0x90, 0x1f, 0x90, 0, 0, 0x0e, 0x10,
+    ];
+    let announcement = [0u8, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 7];
+
+    let mut decoder = Decoder::new();
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        black_box(decoder.feed(client, black_box(&request))).unwrap();
+        black_box(decoder.feed(gateway, black_box(&response))).unwrap();
+        black_box(decoder.feed(gateway, black_box(&announcement))).unwrap();
+    }
+    let elapsed = start.elapsed();
+    let datagrams = f64::from(ROUNDS * 3);
+    println!(
+        "decoded {} datagrams in {:?}, {:.0} ns/datagram",
+        datagrams,
+        elapsed,
+        elapsed.as_nanos() as f64 / datagrams
+    );
+}
@@ -0,0 +1,132 @@
+//! `AsyncUdpSocket` implemented over a pair of in-memory channels, talking to a gateway
+//! emulated in the same process. No network is touched:
+//!
+//!     cargo run --example channel_socket
+//!     cargo test --example channel_socket
+
+use std::io;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use async_channel::{Receiver, Sender};
+use async_trait::async_trait;
+use futures_lite::future;
+use natpmp::*;
+
+/// Each channel message is one datagram.
+struct ChannelSocket {
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+}
+
+#[async_trait]
+impl AsyncUdpSocket for ChannelSocket {
+    async fn connect(&self, _addr: &str) -> io::Result<()> {
+        // the other end of the channels is the gateway
+        Ok(())
+    }
+
+    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.tx
+            .send(buf.to_vec())
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let datagram = self
+            .rx
+            .recv()
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        // the rest of a datagram larger than `buf` is lost, like with udp
+        let n = datagram.len().min(buf.len());
+        buf[..n].copy_from_slice(&datagram[..n]);
+        Ok(n)
+    }
+
+    async fn recv_timeout(&self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        // the default ignores the timeout, so a lost response would hang the retries
+        future::or(self.recv(buf), async {
+            async_io::Timer::after(timeout).await;
+            Err(io::ErrorKind::TimedOut.into())
+        })
+        .await
+    }
+
+    // `local_addr`, `send_to`, `recv_from` and `recv_from_timeout` keep their defaults,
+    // they are only needed by `SocketMode::Unconnected`
+}
+
+/// Answer requests until the client goes away, granting every mapping as requested.
+async fn gateway(requests: Receiver<Vec<u8>>, responses: Sender<Vec<u8>>) {
+    let epoch = 1u32.to_be_bytes();
+    while let Ok(request) = requests.recv().await {
+        let response = match decode_request(&request) {
+            Ok(RequestKind::PublicAddress) => {
+                let mut r = vec![0, 128, 0, 0];
+                r.extend_from_slice(&epoch);
+                r.extend_from_slice(&Ipv4Addr::new(203, 0, 113, 7).octets());
+                r
+            }
+            Ok(RequestKind::Mapping { .. }) => {
+                // private port, public port and lifetime are echoed from the request
+                let mut r = vec![0, 128 + request[1], 0, 0];
+                r.extend_from_slice(&epoch);
+                r.extend_from_slice(&request[4..12]);
+                r
+            }
+            Err(_) => continue,
+        };
+        if responses.send(response).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Query the external address and map a port through the emulated gateway.
+fn round_trip() -> Result<(Ipv4Addr, MappingResponse)> {
+    let (client_tx, gateway_rx) = async_channel::unbounded();
+    let (gateway_tx, client_rx) = async_channel::unbounded();
+    let socket = ChannelSocket {
+        tx: client_tx,
+        rx: client_rx,
+    };
+
+    let client = async move {
+        let n = new_natpmp_async_with(socket, Ipv4Addr::new(192, 168, 0, 1));
+        let address = n.external_address().await?;
+        let mapping = n.map_port(Protocol::TCP, 8080, 8080, 3600).await?;
+        // dropping `n` closes the channels and stops the gateway
+        Ok((address, mapping))
+    };
+    let (result, ()) = future::block_on(future::zip(client, gateway(gateway_rx, gateway_tx)));
+    result
+}
+
+fn main() -> Result<()> {
+    let (address, mapping) = round_trip()?;
+    println!("external address: {}", address);
+    println!(
+        "tcp 8080 mapped to public port {} for {:?}",
+        mapping.public_port(),
+        mapping.lifetime()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        let (address, mapping) = round_trip()?;
+        assert_eq!(address, Ipv4Addr::new(203, 0, 113, 7));
+        assert_eq!(mapping.private_port(), 8080);
+        assert_eq!(mapping.public_port(), 8080);
+        assert_eq!(mapping.lifetime().as_secs(), 3600);
+        Ok(())
+    }
+}
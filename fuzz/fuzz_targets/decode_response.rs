@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use natpmp::{decode_response_with_extra, Response};
+
+fuzz_target!(|data: &[u8]| {
+    // must never panic, whatever the gateway sends
+    if let Ok((r, extra)) = decode_response_with_extra(data) {
+        let standard = match r {
+            Response::Gateway(_) => 12,
+            Response::UDP(_) | Response::TCP(_) => 16,
+        };
+        assert_eq!(extra.len(), data.len() - standard);
+    }
+});
@@ -1,11 +1,19 @@
-use std::net::{Ipv4Addr, SocketAddrV4};
 use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
 
 use async_std::net::UdpSocket;
 use async_trait::async_trait;
 
 use super::*;
-use crate::asynchronous::{new_natpmp_async_with, AsyncUdpSocket, NatpmpAsync};
+use crate::asynchronous::{new_natpmp_async_with_mode, AsyncUdpBind, AsyncUdpSocket, NatpmpAsync};
+
+#[async_trait]
+impl AsyncUdpBind for UdpSocket {
+    async fn bind(addr: SocketAddr) -> io::Result<UdpSocket> {
+        UdpSocket::bind(addr).await
+    }
+}
 
 #[async_trait]
 impl AsyncUdpSocket for UdpSocket {
@@ -20,6 +28,30 @@ impl AsyncUdpSocket for UdpSocket {
     async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
         self.recv(buf).await
     }
+
+    async fn recv_timeout(&self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        async_std::io::timeout(timeout, self.recv(buf)).await
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.local_addr()
+    }
+
+    async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.send_to(buf, addr).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.recv_from(buf).await
+    }
+
+    async fn recv_from_timeout(
+        &self,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> io::Result<(usize, SocketAddr)> {
+        async_std::io::timeout(timeout, self.recv_from(buf)).await
+    }
 }
 
 pub async fn new_async_std_natpmp() -> Result<NatpmpAsync<UdpSocket>> {
@@ -28,13 +60,54 @@ pub async fn new_async_std_natpmp() -> Result<NatpmpAsync<UdpSocket>> {
 }
 
 pub async fn new_async_std_natpmp_with(gateway: Ipv4Addr) -> Result<NatpmpAsync<UdpSocket>> {
-    let s = UdpSocket::bind("0.0.0.0:0")
+    new_async_std_natpmp_with_local_port(gateway, 0).await
+}
+
+/// Create a NAT-PMP object with a specified gateway, sending from a fixed local port.
+///
+/// See [`Natpmp::new_with_local_port`](struct.Natpmp.html#method.new_with_local_port).
+pub async fn new_async_std_natpmp_with_local_port(
+    gateway: Ipv4Addr,
+    local_port: u16,
+) -> Result<NatpmpAsync<UdpSocket>> {
+    new_async_std_natpmp_with_mode(gateway, local_port, SocketMode::Connected).await
+}
+
+/// Create a NAT-PMP object with a specified gateway, local port and socket mode.
+///
+/// See [`Natpmp::new_with_mode`](struct.Natpmp.html#method.new_with_mode).
+pub async fn new_async_std_natpmp_with_mode(
+    gateway: Ipv4Addr,
+    local_port: u16,
+    mode: SocketMode,
+) -> Result<NatpmpAsync<UdpSocket>> {
+    let s = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, local_port))
         .await
-        .map_err(|e| Error::NATPMP_ERR_SOCKETERROR)?;
+        .map_err(bind_error)?;
     let gateway_sockaddr = SocketAddrV4::new(gateway, NATPMP_PORT);
-    if s.connect(gateway_sockaddr).await.is_err() {
+    if mode == SocketMode::Connected && s.connect(gateway_sockaddr).await.is_err() {
         return Err(Error::NATPMP_ERR_CONNECTERR);
     }
-    let n = new_natpmp_async_with(s, gateway);
+    let n = new_natpmp_async_with_mode(s, gateway, mode);
     Ok(n)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::lossy_gateway;
+    use crate::Protocol;
+
+    #[test]
+    fn test_retransmission_timer() {
+        let gateway = Ipv4Addr::new(127, 0, 0, 20);
+        let g = lossy_gateway(gateway, 2);
+        let m = async_std::task::block_on(async {
+            let n = new_async_std_natpmp_with(gateway).await?;
+            n.map_port(Protocol::TCP, 9000, 9000, 60).await
+        });
+        // answered after timing out twice
+        assert_eq!(m.map(|m| m.public_port()), Ok(9000));
+        assert_eq!(g.join().unwrap(), 3);
+    }
+}
@@ -1,11 +1,27 @@
 use std::io;
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use async_trait::async_trait;
 use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
 
-use crate::asynchronous::{new_natpmp_async_with, AsyncUdpSocket, NatpmpAsync};
-use crate::{get_default_gateway, Error, Result, NATPMP_PORT};
+use crate::asynchronous::{new_natpmp_async_with_mode, AsyncUdpBind, AsyncUdpSocket, NatpmpAsync};
+use crate::{
+    bind_error, get_default_gateway, lifetime_secs, Error, MappingResponse, Protocol, Result,
+    SocketMode, NATPMP_PORT,
+};
+
+/// Wait before renewing again when a renewal of a held mapping failed.
+const RENEWAL_RETRY: Duration = Duration::from_secs(10);
+
+#[async_trait]
+impl AsyncUdpBind for UdpSocket {
+    async fn bind(addr: SocketAddr) -> io::Result<UdpSocket> {
+        UdpSocket::bind(addr).await
+    }
+}
 
 #[async_trait]
 impl AsyncUdpSocket for UdpSocket {
@@ -20,6 +36,34 @@ impl AsyncUdpSocket for UdpSocket {
     async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
         self.recv(buf).await
     }
+
+    async fn recv_timeout(&self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        tokio::time::timeout(timeout, self.recv(buf))
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.local_addr()
+    }
+
+    async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.send_to(buf, addr).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.recv_from(buf).await
+    }
+
+    async fn recv_from_timeout(
+        &self,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> io::Result<(usize, SocketAddr)> {
+        tokio::time::timeout(timeout, self.recv_from(buf))
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+    }
 }
 
 pub async fn new_tokio_natpmp() -> Result<NatpmpAsync<UdpSocket>> {
@@ -28,13 +72,272 @@ pub async fn new_tokio_natpmp() -> Result<NatpmpAsync<UdpSocket>> {
 }
 
 pub async fn new_tokio_natpmp_with(gateway: Ipv4Addr) -> Result<NatpmpAsync<UdpSocket>> {
-    let s = UdpSocket::bind("0.0.0.0:0")
+    new_tokio_natpmp_with_local_port(gateway, 0).await
+}
+
+/// Create a NAT-PMP object with a specified gateway, sending from a fixed local port.
+///
+/// See [`Natpmp::new_with_local_port`](struct.Natpmp.html#method.new_with_local_port).
+pub async fn new_tokio_natpmp_with_local_port(
+    gateway: Ipv4Addr,
+    local_port: u16,
+) -> Result<NatpmpAsync<UdpSocket>> {
+    new_tokio_natpmp_with_mode(gateway, local_port, SocketMode::Connected).await
+}
+
+/// Create a NAT-PMP object with a specified gateway, local port and socket mode.
+///
+/// See [`Natpmp::new_with_mode`](struct.Natpmp.html#method.new_with_mode).
+pub async fn new_tokio_natpmp_with_mode(
+    gateway: Ipv4Addr,
+    local_port: u16,
+    mode: SocketMode,
+) -> Result<NatpmpAsync<UdpSocket>> {
+    let s = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, local_port))
         .await
-        .map_err(|e| Error::NATPMP_ERR_SOCKETERROR)?;
+        .map_err(bind_error)?;
     let gateway_sockaddr = SocketAddrV4::new(gateway, NATPMP_PORT);
-    if s.connect(gateway_sockaddr).await.is_err() {
+    if mode == SocketMode::Connected && s.connect(gateway_sockaddr).await.is_err() {
         return Err(Error::NATPMP_ERR_CONNECTERR);
     }
-    let n = new_natpmp_async_with(s, gateway);
+    let n = new_natpmp_async_with_mode(s, gateway, mode);
     Ok(n)
 }
+
+/// A mapping kept renewed in the background until the guard is released or dropped, see
+/// [`NatpmpAsync::hold_mapping`](struct.NatpmpAsync.html#method.hold_mapping).
+///
+/// Dropping the guard destroys the mapping on a spawned task, on a best-effort basis: the
+/// outcome is only logged, and nothing is sent if no tokio runtime is running or if it shuts
+/// down first, the mapping then lasting until its lifetime expires. Use
+/// [`release`](struct.MappingGuard.html#method.release) to wait for the deletion and learn
+/// whether it succeeded.
+pub struct MappingGuard<S>
+where
+    S: AsyncUdpSocket + Send + Sync + 'static,
+{
+    client: Arc<NatpmpAsync<S>>,
+    protocol: Protocol,
+    private_port: u16,
+    mapping: Arc<Mutex<MappingResponse>>,
+    renewal: JoinHandle<()>,
+    released: bool,
+}
+
+impl<S> MappingGuard<S>
+where
+    S: AsyncUdpSocket + Send + Sync + 'static,
+{
+    /// Latest response of the gateway, updated by every renewal.
+    pub fn mapping(&self) -> MappingResponse {
+        *self.mapping.lock().unwrap()
+    }
+
+    /// Public/external port currently granted by the gateway.
+    pub fn public_port(&self) -> u16 {
+        self.mapping.lock().unwrap().public_port()
+    }
+
+    /// Stop renewing and destroy the mapping.
+    ///
+    /// # Errors
+    /// See [`NatpmpAsync::destroy_mapping`](struct.NatpmpAsync.html#method.destroy_mapping).
+    pub async fn release(mut self) -> Result<()> {
+        self.renewal.abort();
+        self.released = true;
+        self.client
+            .destroy_mapping(self.protocol, self.private_port)
+            .await
+    }
+}
+
+impl<S> Drop for MappingGuard<S>
+where
+    S: AsyncUdpSocket + Send + Sync + 'static,
+{
+    fn drop(&mut self) {
+        self.renewal.abort();
+        if self.released {
+            return;
+        }
+        let runtime = match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => runtime,
+            Err(_) => return,
+        };
+        let client = self.client.clone();
+        let (protocol, private_port) = (self.protocol, self.private_port);
+        runtime.spawn(async move {
+            if let Err(e) = client.destroy_mapping(protocol, private_port).await {
+                log::warn!(
+                    "destroying {:?} mapping of port {} failed: {}",
+                    protocol,
+                    private_port,
+                    e
+                );
+            }
+        });
+    }
+}
+
+impl<S> NatpmpAsync<S>
+where
+    S: AsyncUdpSocket + Send + Sync + 'static,
+{
+    /// Request a port mapping and keep it for as long as the returned guard lives.
+    ///
+    /// A tokio task renews the mapping at half its granted lifetime with the granted public
+    /// port and lifetime, retrying every 10 seconds after a failure. The mapping is destroyed
+    /// by [`MappingGuard::release`](struct.MappingGuard.html#method.release) or, best effort,
+    /// when the guard is dropped. Must be called within a tokio runtime.
+    ///
+    /// # Errors
+    /// See [`map_port`](struct.NatpmpAsync.html#method.map_port).
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use std::sync::Arc;
+    /// use natpmp::*;
+    ///
+    /// # async fn run() -> Result<()> {
+    /// let n = Arc::new(new_tokio_natpmp().await?);
+    /// let guard = n.hold_mapping(Protocol::TCP, 443, 443, 3600).await?;
+    /// println!("serving on public port {}", guard.public_port());
+    /// // serve until shutdown
+    /// guard.release().await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn hold_mapping(
+        self: &Arc<Self>,
+        protocol: Protocol,
+        private_port: u16,
+        public_port: u16,
+        lifetime: u32,
+    ) -> Result<MappingGuard<S>> {
+        let m = self
+            .map_port(protocol, private_port, public_port, lifetime)
+            .await?;
+        let mapping = Arc::new(Mutex::new(m));
+        let renewal = tokio::spawn(renew_held_mapping(
+            self.clone(),
+            protocol,
+            private_port,
+            mapping.clone(),
+        ));
+        Ok(MappingGuard {
+            client: self.clone(),
+            protocol,
+            private_port,
+            mapping,
+            renewal,
+            released: false,
+        })
+    }
+}
+
+async fn renew_held_mapping<S>(
+    client: Arc<NatpmpAsync<S>>,
+    protocol: Protocol,
+    private_port: u16,
+    mapping: Arc<Mutex<MappingResponse>>,
+) where
+    S: AsyncUdpSocket + Send + Sync + 'static,
+{
+    let mut delay = *mapping.lock().unwrap().lifetime() / 2;
+    loop {
+        // a gateway granting no lifetime must not be renewed in a busy loop
+        tokio::time::sleep(delay.max(Duration::from_secs(1))).await;
+        let (public_port, lifetime) = {
+            let m = mapping.lock().unwrap();
+            (m.public_port(), *m.lifetime())
+        };
+        let renewed = match lifetime_secs(lifetime) {
+            Ok(lifetime) => {
+                client
+                    .map_port(protocol, private_port, public_port, lifetime)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        match renewed {
+            Ok(m) => {
+                delay = *m.lifetime() / 2;
+                *mapping.lock().unwrap() = m;
+            }
+            Err(e) => {
+                log::warn!(
+                    "renewing {:?} mapping of port {} failed: {}",
+                    protocol,
+                    private_port,
+                    e
+                );
+                delay = RENEWAL_RETRY;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{fake_gateway, lossy_gateway, mapping_response};
+    use crate::Natpmp;
+
+    #[test]
+    fn test_retransmission_timer() {
+        let gateway = Ipv4Addr::new(127, 0, 0, 19);
+        let g = lossy_gateway(gateway, 2);
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let m = rt.block_on(async {
+            let n = new_tokio_natpmp_with(gateway).await?;
+            n.map_port(Protocol::TCP, 9000, 9000, 60).await
+        });
+        // answered after timing out twice
+        assert_eq!(m.map(|m| m.public_port()), Ok(9000));
+        assert_eq!(g.join().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_hold_mapping() {
+        let gateway = Ipv4Addr::new(127, 0, 0, 28);
+        // renewed after a second
+        let g = fake_gateway(gateway, 3, |request| mapping_response(request, 4430, 2));
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let n = Arc::new(new_tokio_natpmp_with(gateway).await?);
+            let guard = n.hold_mapping(Protocol::TCP, 443, 443, 60).await?;
+            assert_eq!(guard.public_port(), 4430);
+            tokio::time::sleep(Duration::from_millis(1500)).await;
+            guard.release().await
+        })
+        .unwrap();
+        let requests = g.join().unwrap();
+        assert_eq!(
+            requests[1],
+            Natpmp::build_mapping_request(Protocol::TCP, 443, 4430, 2).unwrap()
+        );
+        assert_eq!(
+            requests[2],
+            Natpmp::build_mapping_request(Protocol::TCP, 443, 0, 0).unwrap()
+        );
+
+        // destroyed when dropped
+        let gateway = Ipv4Addr::new(127, 0, 0, 29);
+        let g = fake_gateway(gateway, 2, |request| mapping_response(request, 8443, 60));
+        rt.block_on(async {
+            let n = Arc::new(new_tokio_natpmp_with(gateway).await.unwrap());
+            drop(n.hold_mapping(Protocol::UDP, 8443, 8443, 60).await.unwrap());
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        });
+        assert_eq!(
+            g.join().unwrap()[1],
+            Natpmp::build_mapping_request(Protocol::UDP, 8443, 0, 0).unwrap()
+        );
+    }
+}
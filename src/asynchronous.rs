@@ -1,15 +1,21 @@
 use std::io;
-use std::net::{Ipv4Addr, SocketAddrV4};
-use std::time::Duration;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
 
 use crate::{
-    convert_to, get_default_gateway, Error, GatewayResponse, MappingResponse, Protocol, Response,
-    Result, NATPMP_MAX_ATTEMPS, NATPMP_PORT,
+    bind_error, check_response_header, decode_response, get_default_gateway, to_hex, Error, Jitter,
+    MappingResponse, Natpmp, Protocol, Response, Result, RetransmissionProfile, SocketMode,
+    NATPMP_MIN_WAIT, NATPMP_PORT, NATPMP_RECV_BUFFER_SIZE,
 };
 
 /// A wrapper trait for async udpsocket.
+///
+/// The provided methods need the socket to be `Sync`, as does
+/// [`NatpmpAsync`](struct.NatpmpAsync.html). A socket implemented with `async_trait` already
+/// is, its futures borrowing it must be `Send`.
 #[async_trait]
 pub trait AsyncUdpSocket {
     async fn connect(&self, addr: &str) -> io::Result<()>;
@@ -17,15 +23,131 @@ pub trait AsyncUdpSocket {
     async fn send(&self, buf: &[u8]) -> io::Result<usize>;
 
     async fn recv(&self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Receive, failing with `io::ErrorKind::TimedOut` after `timeout`.
+    ///
+    /// Runtime specific, the default ignores the timeout.
+    async fn recv_timeout(&self, buf: &mut [u8], _timeout: Duration) -> io::Result<usize> {
+        self.recv(buf).await
+    }
+
+    /// Local address the socket is bound to.
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Send to `addr`, required by [`SocketMode::Unconnected`](enum.SocketMode.html#variant.Unconnected).
+    async fn send_to(&self, _buf: &[u8], _addr: SocketAddr) -> io::Result<usize> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Receive from any source, required by [`SocketMode::Unconnected`](enum.SocketMode.html#variant.Unconnected).
+    async fn recv_from(&self, _buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Receive from any source, failing with `io::ErrorKind::TimedOut` after `timeout`.
+    ///
+    /// Runtime specific, the default ignores the timeout.
+    async fn recv_from_timeout(
+        &self,
+        buf: &mut [u8],
+        _timeout: Duration,
+    ) -> io::Result<(usize, SocketAddr)> {
+        self.recv_from(buf).await
+    }
+}
+
+/// Socket the crate can create itself, implemented by the runtime backends, see
+/// [`connect`](fn.connect.html).
+#[async_trait]
+pub trait AsyncUdpBind: AsyncUdpSocket + Sized {
+    /// Bind a socket to `addr`.
+    async fn bind(addr: SocketAddr) -> io::Result<Self>;
+}
+
+/// Public address requests sent by [`NatpmpAsync::is_supported`](struct.NatpmpAsync.html#method.is_supported).
+const NATPMP_PROBE_ATTEMPTS: u32 = 3;
+
+/// How long [`NatpmpAsync::ping`](struct.NatpmpAsync.html#method.ping) waits for a reply.
+const NATPMP_PING_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Error of a receive failure which retransmitting cannot fix, if it is one.
+///
+/// A refused connection is an ICMP port unreachable from a gateway without NAT-PMP.
+fn definitive_recv_error(e: &io::Error) -> Option<Error> {
+    match e.kind() {
+        io::ErrorKind::ConnectionRefused => Some(Error::NATPMP_ERR_NOGATEWAYSUPPORT),
+        io::ErrorKind::HostUnreachable | io::ErrorKind::NetworkUnreachable => {
+            Some(Error::NATPMP_ERR_NETWORKFAILURE)
+        }
+        _ => None,
+    }
 }
 
 /// NAT-PMP async client
+///
+/// The client can be shared between tasks, e.g. in an `Arc`. Operations sending a request and
+/// reading its response, like [`map_port`](struct.NatpmpAsync.html#method.map_port), serialize
+/// internally so their responses are not mixed up. Requests sent with the `send_*` methods and
+/// read with [`read_response_or_retry`](struct.NatpmpAsync.html#method.read_response_or_retry)
+/// are not serialized.
 pub struct NatpmpAsync<S>
 where
     S: AsyncUdpSocket,
 {
     s: S,
     gateway: Ipv4Addr,
+    mode: SocketMode,
+    profile: RetransmissionProfile,
+    jitter: Mutex<Jitter>,
+    pending_request: Mutex<Option<Vec<u8>>>,
+    exchange: async_lock::Mutex<()>,
+}
+
+/// Discover the default gateway, connect to it and check it supports NAT-PMP.
+///
+/// The runtime is picked with the socket type, e.g. `tokio::net::UdpSocket` with the `tokio`
+/// feature or `async_std::net::UdpSocket` with the `async-std` feature.
+///
+/// # Errors
+/// Each stage fails with its own error:
+/// * See [`get_default_gateway`](fn.get_default_gateway.html) for gateway discovery
+/// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR) if
+///   the socket could not be bound
+/// * [`Error::NATPMP_ERR_CONNECTERR`](enum.Error.html#variant.NATPMP_ERR_CONNECTERR)
+/// * [`Error::NATPMP_ERR_NETWORKFAILURE`](enum.Error.html#variant.NATPMP_ERR_NETWORKFAILURE)
+///   if the probe could not be sent
+/// * [`Error::NATPMP_ERR_NOGATEWAYSUPPORT`](enum.Error.html#variant.NATPMP_ERR_NOGATEWAYSUPPORT)
+///   if the gateway did not answer the probe, see
+///   [`NatpmpAsync::is_supported`](struct.NatpmpAsync.html#method.is_supported)
+///
+/// # Examples
+/// ```no_run
+/// # #[cfg(feature = "tokio")]
+/// # async fn run() -> natpmp::Result<()> {
+/// let n = natpmp::connect::<tokio::net::UdpSocket>().await?;
+/// n.send_public_address_request().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn connect<S>() -> Result<NatpmpAsync<S>>
+where
+    S: AsyncUdpBind + Sync,
+{
+    let gateway = get_default_gateway()?;
+    let s = S::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))
+        .await
+        .map_err(bind_error)?;
+    let addr = SocketAddrV4::new(gateway, NATPMP_PORT).to_string();
+    s.connect(&addr)
+        .await
+        .map_err(|_| Error::NATPMP_ERR_CONNECTERR)?;
+    let n = new_natpmp_async_with(s, gateway);
+    if !n.is_supported().await? {
+        return Err(Error::NATPMP_ERR_NOGATEWAYSUPPORT);
+    }
+    Ok(n)
 }
 
 /// Create a NAT-PMP object with async udpsocket and gateway
@@ -33,126 +155,754 @@ pub fn new_natpmp_async_with<S>(s: S, gateway: Ipv4Addr) -> NatpmpAsync<S>
 where
     S: AsyncUdpSocket,
 {
-    NatpmpAsync { s, gateway }
+    new_natpmp_async_with_mode(s, gateway, SocketMode::Connected)
 }
 
-impl<S> NatpmpAsync<S>
+/// Create a NAT-PMP object with async udpsocket, gateway and socket mode
+///
+/// `s` must already be connected to the gateway in
+/// [`SocketMode::Connected`](enum.SocketMode.html#variant.Connected).
+pub fn new_natpmp_async_with_mode<S>(s: S, gateway: Ipv4Addr, mode: SocketMode) -> NatpmpAsync<S>
 where
     S: AsyncUdpSocket,
+{
+    NatpmpAsync {
+        s,
+        gateway,
+        mode,
+        profile: RetransmissionProfile::rfc_default(),
+        jitter: Mutex::new(Jitter::new()),
+        pending_request: Mutex::new(None),
+        exchange: async_lock::Mutex::new(()),
+    }
+}
+
+impl<S> NatpmpAsync<S>
+where
+    S: AsyncUdpSocket + Sync,
 {
     /// NAT-PMP gateway address.
     pub fn gateway(&self) -> &Ipv4Addr {
         &self.gateway
     }
 
-    pub async fn send_public_address_request(&mut self) -> Result<()> {
-        let mut request = [0_u8; 2];
-        let n = self
-            .s
-            .send(&request[..])
-            .await
-            .map_err(|e| Error::NATPMP_ERR_NETWORKFAILURE)?;
+    /// Switch to another gateway.
+    ///
+    /// In [`SocketMode::Connected`](enum.SocketMode.html#variant.Connected) the socket is
+    /// connected to `gateway` first, and the gateway is unchanged if that fails, so
+    /// [`gateway`](struct.NatpmpAsync.html#method.gateway) is always the peer of the socket.
+    /// A pending request is dropped.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_CONNECTERR`](enum.Error.html#variant.NATPMP_ERR_CONNECTERR)
+    pub async fn set_gateway(&mut self, gateway: Ipv4Addr) -> Result<()> {
+        if self.mode == SocketMode::Connected {
+            let addr = SocketAddrV4::new(gateway, NATPMP_PORT).to_string();
+            self.s
+                .connect(&addr)
+                .await
+                .map_err(|_| Error::NATPMP_ERR_CONNECTERR)?;
+        }
+        self.gateway = gateway;
+        self.set_pending_request(None);
+        Ok(())
+    }
+
+    /// Socket mode chosen at construction.
+    pub fn mode(&self) -> SocketMode {
+        self.mode
+    }
+
+    /// Set how requests are retransmitted, see [`Natpmp::set_retransmission_profile`](struct.Natpmp.html#method.set_retransmission_profile).
+    pub fn set_retransmission_profile(&mut self, profile: RetransmissionProfile) {
+        self.profile = profile;
+    }
+
+    /// Retransmission profile.
+    pub fn retransmission_profile(&self) -> &RetransmissionProfile {
+        &self.profile
+    }
+
+    /// Randomize every retransmission timeout, see
+    /// [`Natpmp::set_jitter`](struct.Natpmp.html#method.set_jitter).
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_INVALIDARGS`](enum.Error.html#variant.NATPMP_ERR_INVALIDARGS) if
+    ///   `jitter` is not within `0.0..=1.0`
+    pub fn set_jitter(&mut self, jitter: f32) -> Result<()> {
+        self.jitter.lock().unwrap().set(jitter)
+    }
+
+    /// Retransmission jitter.
+    pub fn jitter(&self) -> f32 {
+        self.jitter.lock().unwrap().fraction()
+    }
+
+    /// Seed the random source of the jitter, see
+    /// [`Natpmp::set_jitter_seed`](struct.Natpmp.html#method.set_jitter_seed).
+    pub fn set_jitter_seed(&mut self, seed: u64) {
+        self.jitter.lock().unwrap().set_seed(seed);
+    }
+
+    /// Timeout of the given attempt according to the retransmission profile, with jitter applied.
+    fn retransmission_delay(&self, attempt: u32) -> Duration {
+        self.jitter
+            .lock()
+            .unwrap()
+            .apply(self.profile.delay(attempt))
+    }
+
+    /// Local address the socket is bound to.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR),
+    ///   also if the socket does not implement [`AsyncUdpSocket::local_addr`](trait.AsyncUdpSocket.html#method.local_addr)
+    pub fn local_addr(&self) -> Result<SocketAddrV4> {
+        match self.s.local_addr() {
+            Ok(SocketAddr::V4(addr)) => Ok(addr),
+            _ => Err(Error::NATPMP_ERR_SOCKETERROR),
+        }
+    }
+
+    async fn send_request(&self, request: &[u8]) -> Result<()> {
+        log::debug!("sending to {}: {}", self.gateway, to_hex(request));
+        let sent = match self.mode {
+            SocketMode::Connected => self.s.send(request).await,
+            SocketMode::Unconnected => {
+                let gateway = SocketAddr::from((self.gateway, NATPMP_PORT));
+                self.s.send_to(request, gateway).await
+            }
+        };
+        let n = sent.map_err(|_| Error::NATPMP_ERR_NETWORKFAILURE)?;
         if n != request.len() {
             return Err(Error::NATPMP_ERR_NETWORKFAILURE);
         }
         Ok(())
     }
 
+    /// Receive a datagram from the gateway, skipping other sources when unconnected.
+    async fn recv_response(&self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        if self.mode == SocketMode::Connected {
+            let n = self.s.recv_timeout(buf, timeout).await?;
+            log::debug!("received from {}: {}", self.gateway, to_hex(&buf[..n]));
+            return Ok(n);
+        }
+        let gateway = SocketAddr::from((self.gateway, NATPMP_PORT));
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_millis(0) {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            let (n, source) = self.s.recv_from_timeout(buf, remaining).await?;
+            if source == gateway {
+                log::debug!("received from {}: {}", source, to_hex(&buf[..n]));
+                return Ok(n);
+            }
+        }
+    }
+
+    fn set_pending_request(&self, request: Option<Vec<u8>>) {
+        *self.pending_request.lock().unwrap() = request;
+    }
+
+    pub async fn send_public_address_request(&self) -> Result<()> {
+        let request = Natpmp::build_public_address_request();
+        self.set_pending_request(Some(request.to_vec()));
+        self.send_request(&request).await
+    }
+
     pub async fn send_port_mapping_request(
-        &mut self,
+        &self,
         protocol: Protocol,
         private_port: u16,
         public_port: u16,
         lifetime: u32,
     ) -> Result<()> {
-        let mut request = [0_u8; 12];
-        request[1] = match protocol {
-            Protocol::UDP => 1,
-            _ => 2,
-        };
-        request[2] = 0; // reserved
-        request[3] = 0; // reserved
-                        // private port
-        request[4] = (private_port >> 8 & 0xff) as u8;
-        request[5] = (private_port & 0xff) as u8;
-        // public port
-        request[6] = (public_port >> 8 & 0xff) as u8;
-        request[7] = (public_port & 0xff) as u8;
-        // lifetime
-        request[8] = ((lifetime >> 24) & 0xff) as u8;
-        request[9] = ((lifetime >> 16) & 0xff) as u8;
-        request[10] = ((lifetime >> 8) & 0xff) as u8;
-        request[11] = (lifetime & 0xff) as u8;
-
-        let n = self
-            .s
-            .send(&request[..])
+        let request = Natpmp::build_mapping_request(protocol, private_port, public_port, lifetime)?;
+        self.set_pending_request(Some(request.to_vec()));
+        self.send_request(&request).await
+    }
+
+    /// Request the public address of the gateway and wait for the answer.
+    ///
+    /// # Errors
+    /// See [`read_response_or_retry`](struct.NatpmpAsync.html#method.read_response_or_retry).
+    pub async fn external_address(&self) -> Result<Ipv4Addr> {
+        let _exchange = self.exchange.lock().await;
+        self.send_public_address_request().await?;
+        match self.read_response_or_retry().await? {
+            Response::Gateway(gr) => Ok(*gr.public_address()),
+            _ => Err(Error::NATPMP_ERR_UNSUPPORTEDOPCODE),
+        }
+    }
+
+    /// Request a port mapping and wait for the answer.
+    ///
+    /// The gateway may assign a public port other than `public_port`.
+    ///
+    /// # Errors
+    /// See [`read_response_or_retry`](struct.NatpmpAsync.html#method.read_response_or_retry).
+    pub async fn map_port(
+        &self,
+        protocol: Protocol,
+        private_port: u16,
+        public_port: u16,
+        lifetime: u32,
+    ) -> Result<MappingResponse> {
+        let _exchange = self.exchange.lock().await;
+        self.send_port_mapping_request(protocol, private_port, public_port, lifetime)
+            .await?;
+        match (protocol, self.read_response_or_retry().await?) {
+            (Protocol::UDP, Response::UDP(m)) | (Protocol::TCP, Response::TCP(m)) => Ok(m),
+            _ => Err(Error::NATPMP_ERR_UNSUPPORTEDOPCODE),
+        }
+    }
+
+    /// Destroy the mapping of `private_port`, see
+    /// [`Natpmp::destroy_mapping`](struct.Natpmp.html#method.destroy_mapping).
+    ///
+    /// # Errors
+    /// See [`map_port`](struct.NatpmpAsync.html#method.map_port).
+    pub async fn destroy_mapping(&self, protocol: Protocol, private_port: u16) -> Result<()> {
+        self.map_port(protocol, private_port, 0, 0)
             .await
-            .map_err(|e| Error::NATPMP_ERR_NETWORKFAILURE)?;
-        if n != request.len() {
-            return Err(Error::NATPMP_ERR_NETWORKFAILURE);
+            .map(|_| ())
+    }
+
+    /// Whether the gateway answers NAT-PMP requests.
+    ///
+    /// Sends a public address request up to 3 times, waiting 250ms, 500ms and 1s for an
+    /// answer. Any NAT-PMP response counts, even an error result code. Timeouts depend on
+    /// [`AsyncUdpSocket::recv_timeout`](trait.AsyncUdpSocket.html#method.recv_timeout).
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_NETWORKFAILURE`](enum.Error.html#variant.NATPMP_ERR_NETWORKFAILURE)
+    ///   if the request could not be sent
+    pub async fn is_supported(&self) -> Result<bool> {
+        let _exchange = self.exchange.lock().await;
+        let mut buf = [0_u8; NATPMP_RECV_BUFFER_SIZE];
+        for attempt in 0..NATPMP_PROBE_ATTEMPTS {
+            self.send_public_address_request().await?;
+            let timeout = Duration::from_millis(NATPMP_MIN_WAIT << attempt);
+            match self.read_single_attempt(&mut buf, timeout).await {
+                Ok(_) => return Ok(true),
+                Err(Error::NATPMP_ERR_NOGATEWAYSUPPORT) => return Ok(false),
+                Err(_) => {}
+            }
         }
-        Ok(())
+        Ok(false)
+    }
+
+    /// Round-trip time of a public address request to the gateway.
+    ///
+    /// A single attempt without retransmission, waiting up to 1s for a reply. Any NAT-PMP
+    /// response counts, even an error result code.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_RECVFROM`](enum.Error.html#variant.NATPMP_ERR_RECVFROM) on timeout
+    /// * [`Error::NATPMP_ERR_NOGATEWAYSUPPORT`](enum.Error.html#variant.NATPMP_ERR_NOGATEWAYSUPPORT)
+    ///   if the gateway refused the request
+    /// * [`Error::NATPMP_ERR_NETWORKFAILURE`](enum.Error.html#variant.NATPMP_ERR_NETWORKFAILURE)
+    ///   if the request could not be sent
+    pub async fn ping(&self) -> Result<Duration> {
+        let _exchange = self.exchange.lock().await;
+        let mut buf = [0_u8; NATPMP_RECV_BUFFER_SIZE];
+        let start = Instant::now();
+        self.send_public_address_request().await?;
+        self.read_single_attempt(&mut buf, NATPMP_PING_TIMEOUT)
+            .await?;
+        Ok(start.elapsed())
     }
 
+    /// Wait up to `timeout` for a NAT-PMP response, returns its length.
+    ///
+    /// Datagrams which are not responses are skipped and nothing is retransmitted.
+    async fn read_single_attempt(&self, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_millis(0) {
+                return Err(Error::NATPMP_ERR_RECVFROM);
+            }
+            match self.recv_response(buf, remaining).await {
+                Ok(n) if check_response_header(&buf[..n]).is_ok() => return Ok(n),
+                // skip anything else
+                Ok(_) => continue,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                    return Err(Error::NATPMP_ERR_NOGATEWAYSUPPORT)
+                }
+                Err(_) => return Err(Error::NATPMP_ERR_RECVFROM),
+            }
+        }
+    }
+
+    /// Read NAT-PMP response, retrying on receive failures.
+    ///
+    /// Each attempt waits according to the retransmission profile, the last request sent is
+    /// retransmitted when an attempt times out. Datagrams which are not NAT-PMP responses are
+    /// skipped without using up an attempt. When every attempt failed, the error of the last
+    /// skipped datagram is returned if any, e.g.
+    /// [`Error::NATPMP_ERR_MALFORMEDRESPONSE`](enum.Error.html#variant.NATPMP_ERR_MALFORMEDRESPONSE)
+    /// for a truncated one, otherwise
+    /// [`Error::NATPMP_ERR_RECVFROM`](enum.Error.html#variant.NATPMP_ERR_RECVFROM).
+    ///
+    /// Receive errors retransmission cannot fix end the attempts at once:
+    /// [`Error::NATPMP_ERR_NOGATEWAYSUPPORT`](enum.Error.html#variant.NATPMP_ERR_NOGATEWAYSUPPORT)
+    /// for a refused connection,
+    /// [`Error::NATPMP_ERR_NETWORKFAILURE`](enum.Error.html#variant.NATPMP_ERR_NETWORKFAILURE)
+    /// for an unreachable host or network.
     pub async fn read_response_or_retry(&self) -> Result<Response> {
-        let mut buf = [0_u8; 16];
-        let mut retries = 0;
-        while retries < NATPMP_MAX_ATTEMPS {
-            match self.s.recv(&mut buf).await {
-                Err(_) => retries += 1,
-                Ok(n) => {
-                    // version
-                    if buf[0] != 0 {
-                        return Err(Error::NATPMP_ERR_UNSUPPORTEDVERSION);
+        let mut buf = [0_u8; NATPMP_RECV_BUFFER_SIZE];
+        let mut attempt = 1;
+        let mut skipped = None;
+        while attempt <= self.profile.max_attempts() {
+            let timeout = self.retransmission_delay(attempt);
+            match self.recv_response(&mut buf, timeout).await {
+                // interrupted by a signal, not a failed attempt
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    if let Some(error) = definitive_recv_error(&e) {
+                        return Err(error);
                     }
-                    // opcode
-                    if buf[1] < 128 || buf[1] > 130 {
-                        return Err(Error::NATPMP_ERR_UNSUPPORTEDOPCODE);
+                    attempt += 1;
+                    let timed_out = matches!(
+                        e.kind(),
+                        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+                    );
+                    let request = self.pending_request.lock().unwrap().clone();
+                    if let (true, Some(request)) = (timed_out, request) {
+                        if attempt <= self.profile.max_attempts() {
+                            self.send_request(&request).await?;
+                        }
                     }
-                    // result code
-                    let resultcode = u16::from_be(convert_to(&buf[2..4]));
-                    // result
-                    if resultcode != 0 {
-                        return Err(match resultcode {
-                            1 => Error::NATPMP_ERR_UNSUPPORTEDVERSION,
-                            2 => Error::NATPMP_ERR_NOTAUTHORIZED,
-                            3 => Error::NATPMP_ERR_NETWORKFAILURE,
-                            4 => Error::NATPMP_ERR_OUTOFRESOURCES,
-                            5 => Error::NATPMP_ERR_UNSUPPORTEDOPCODE,
-                            _ => Error::NATPMP_ERR_UNDEFINEDERROR,
-                        });
+                }
+                // an empty or short datagram fails as malformed
+                Ok(n) => match check_response_header(&buf[..n]) {
+                    Err(e) => skipped = Some(e),
+                    Ok(_) => return decode_response(&buf[..n]),
+                },
+            }
+        }
+
+        Err(skipped.unwrap_or(Error::NATPMP_ERR_RECVFROM))
+    }
+
+    /// Read every response arriving within `timeout` of the previous one, e.g. the replies to
+    /// requests sent in a burst with the `send_*` methods.
+    ///
+    /// Stops at the first read waiting `timeout` in vain and retransmits nothing. Each datagram
+    /// is decoded with [`decode_response`](fn.decode_response.html), so one which is not a
+    /// valid response is returned as an error in its place. A receive failure other than a
+    /// timeout ends the batch with its error, see
+    /// [`read_response_or_retry`](struct.NatpmpAsync.html#method.read_response_or_retry).
+    /// Not serialized with other reads, which may take some of the responses.
+    pub async fn drain_responses(&self, timeout: Duration) -> Vec<Result<Response>> {
+        let mut buf = [0_u8; NATPMP_RECV_BUFFER_SIZE];
+        let mut responses = Vec::new();
+        loop {
+            match self.recv_response(&mut buf, timeout).await {
+                Ok(n) => responses.push(decode_response(&buf[..n])),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    if let Some(error) = definitive_recv_error(&e) {
+                        responses.push(Err(error));
+                    } else if !matches!(
+                        e.kind(),
+                        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+                    ) {
+                        responses.push(Err(Error::NATPMP_ERR_RECVFROM));
                     }
-                    // epoch
-                    let epoch = u32::from_be(convert_to(&buf[4..8]));
-                    let rsp_type = buf[1] & 0x7f;
-                    return Ok(match rsp_type {
-                        0 => Response::Gateway(GatewayResponse {
-                            epoch,
-                            public_address: Ipv4Addr::from(u32::from_be(convert_to(&buf[8..12]))),
-                        }),
-                        _ => {
-                            let private_port = u16::from_be(convert_to(&buf[8..10]));
-                            let public_port = u16::from_be(convert_to(&buf[10..12]));
-                            let lifetime = u32::from_be(convert_to(&buf[12..16]));
-                            let lifetime = Duration::from_secs(u64::from(lifetime));
-                            let m = MappingResponse {
-                                epoch,
-                                private_port,
-                                public_port,
-                                lifetime,
-                            };
-                            if rsp_type == 1 {
-                                Response::UDP(m)
-                            } else {
-                                Response::TCP(m)
-                            }
-                        }
-                    });
+                    return responses;
                 }
             }
         }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Answer to a request.
+    pub(crate) type Responder = fn(&[u8]) -> Vec<u8>;
+
+    /// Socket replaying scripted receive results, failing once they are used up.
+    ///
+    /// Datagrams are received from `sources` in order, then from the gateway. With `respond`
+    /// each request is answered ahead of the scripted datagrams, the latest first. The timeout
+    /// of every connected receive is recorded in `timeouts`.
+    #[derive(Default)]
+    pub(crate) struct MockSocket {
+        pub(crate) recv: Mutex<VecDeque<io::Result<Vec<u8>>>>,
+        pub(crate) sources: Mutex<VecDeque<SocketAddr>>,
+        pub(crate) sent: Mutex<Vec<Vec<u8>>>,
+        pub(crate) connected: Mutex<Vec<String>>,
+        pub(crate) timeouts: Mutex<Vec<Duration>>,
+        pub(crate) respond: Option<Responder>,
+    }
+
+    impl MockSocket {
+        pub(crate) fn new(recv: Vec<io::Result<Vec<u8>>>) -> MockSocket {
+            MockSocket {
+                recv: Mutex::new(recv.into()),
+                ..MockSocket::default()
+            }
+        }
+    }
+
+    #[async_trait]
+    impl AsyncUdpSocket for MockSocket {
+        async fn connect(&self, addr: &str) -> io::Result<()> {
+            if addr.starts_with("0.0.0.0:") {
+                return Err(io::ErrorKind::AddrNotAvailable.into());
+            }
+            self.connected.lock().unwrap().push(addr.to_string());
+            Ok(())
+        }
+
+        async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+            self.sent.lock().unwrap().push(buf.to_vec());
+            if let Some(respond) = self.respond {
+                self.recv.lock().unwrap().push_front(Ok(respond(buf)));
+            }
+            Ok(buf.len())
+        }
+
+        async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+            // let concurrent exchanges interleave
+            futures_lite::future::yield_now().await;
+            let datagram = self
+                .recv
+                .lock()
+                .unwrap()
+                .pop_front()
+                .unwrap_or_else(|| Err(io::ErrorKind::TimedOut.into()))?;
+            let n = datagram.len().min(buf.len());
+            buf[..n].copy_from_slice(&datagram[..n]);
+            Ok(n)
+        }
+
+        async fn recv_timeout(&self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+            self.timeouts.lock().unwrap().push(timeout);
+            self.recv(buf).await
+        }
+
+        async fn send_to(&self, buf: &[u8], _addr: SocketAddr) -> io::Result<usize> {
+            self.send(buf).await
+        }
+
+        async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            let n = self.recv(buf).await?;
+            let source = self.sources.lock().unwrap().pop_front();
+            Ok((
+                n,
+                source.unwrap_or_else(|| ([192, 168, 0, 1], NATPMP_PORT).into()),
+            ))
+        }
+    }
+
+    pub(crate) fn mock_natpmp(recv: Vec<io::Result<Vec<u8>>>) -> NatpmpAsync<MockSocket> {
+        new_natpmp_async_with(MockSocket::new(recv), Ipv4Addr::new(192, 168, 0, 1))
+    }
+
+    const GATEWAY_RESPONSE: [u8; 12] = [0, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 7];
+
+    #[test]
+    fn test_skip_garbage() {
+        let n = mock_natpmp(vec![Ok(b"garbage".to_vec()), Ok(GATEWAY_RESPONSE.to_vec())]);
+        let r = futures_lite::future::block_on(n.read_response_or_retry());
+        match r {
+            Ok(Response::Gateway(gr)) => {
+                assert_eq!(gr.public_address(), &Ipv4Addr::new(203, 0, 113, 7))
+            }
+            _ => panic!("Not a gateway response"),
+        }
+    }
+
+    #[test]
+    fn test_skip_empty() {
+        let n = mock_natpmp(vec![Ok(Vec::new()), Ok(vec![0])]);
+        let r = futures_lite::future::block_on(n.read_response_or_retry());
+        assert_eq!(r, Err(Error::NATPMP_ERR_MALFORMEDRESPONSE));
+
+        let n = mock_natpmp(vec![Ok(Vec::new()), Ok(GATEWAY_RESPONSE.to_vec())]);
+        let r = futures_lite::future::block_on(n.read_response_or_retry());
+        match r {
+            Ok(Response::Gateway(gr)) => {
+                assert_eq!(gr.public_address(), &Ipv4Addr::new(203, 0, 113, 7))
+            }
+            _ => panic!("Not a gateway response"),
+        }
+    }
+
+    #[test]
+    fn test_interrupted() {
+        // more interruptions than attempts
+        let mut recv: Vec<io::Result<Vec<u8>>> =
+            (0..RetransmissionProfile::default().max_attempts() + 1)
+                .map(|_| Err(io::ErrorKind::Interrupted.into()))
+                .collect();
+        recv.push(Ok(GATEWAY_RESPONSE.to_vec()));
+        let n = mock_natpmp(recv);
+        let r = futures_lite::future::block_on(n.read_response_or_retry());
+        assert!(matches!(r, Ok(Response::Gateway(_))));
+        assert!(n.s.sent.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_is_supported() {
+        let n = mock_natpmp(vec![Ok(b"garbage".to_vec()), Ok(GATEWAY_RESPONSE.to_vec())]);
+        assert_eq!(futures_lite::future::block_on(n.is_supported()), Ok(true));
+        assert_eq!(n.s.sent.lock().unwrap().len(), 1);
+
+        let n = mock_natpmp(Vec::new());
+        assert_eq!(futures_lite::future::block_on(n.is_supported()), Ok(false));
+        // every attempt timed out
+        assert_eq!(n.s.sent.lock().unwrap().len(), 3);
+
+        let n = mock_natpmp(vec![Err(io::ErrorKind::ConnectionRefused.into())]);
+        assert_eq!(futures_lite::future::block_on(n.is_supported()), Ok(false));
+    }
+
+    #[test]
+    fn test_unconnected() {
+        let mut socket = MockSocket::new(vec![
+            Ok(vec![0, 128, 0, 0, 0, 0, 0, 1, 10, 0, 0, 1]),
+            Ok(GATEWAY_RESPONSE.to_vec()),
+        ]);
+        // from the gateway's address but another port
+        socket.sources = Mutex::new(vec![([192, 168, 0, 1], 5350).into()].into());
+        let n = new_natpmp_async_with_mode(
+            socket,
+            Ipv4Addr::new(192, 168, 0, 1),
+            SocketMode::Unconnected,
+        );
+        assert_eq!(n.mode(), SocketMode::Unconnected);
+        assert_eq!(n.local_addr(), Err(Error::NATPMP_ERR_SOCKETERROR));
+        futures_lite::future::block_on(n.send_public_address_request()).unwrap();
+        let r = futures_lite::future::block_on(n.read_response_or_retry());
+        match r {
+            Ok(Response::Gateway(gr)) => {
+                assert_eq!(gr.public_address(), &Ipv4Addr::new(203, 0, 113, 7))
+            }
+            _ => panic!("Not a gateway response"),
+        }
+        assert_eq!(n.s.sent.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_retransmission() {
+        let mut n = mock_natpmp(Vec::new());
+        n.set_retransmission_profile(RetransmissionProfile::fast_fail());
+        futures_lite::future::block_on(n.send_public_address_request()).unwrap();
+        let r = futures_lite::future::block_on(n.read_response_or_retry());
+        assert_eq!(r, Err(Error::NATPMP_ERR_RECVFROM));
+        // sent once, then retransmitted after each attempt but the last
+        let sent = n.s.sent.lock().unwrap();
+        assert_eq!(sent.len(), 3);
+        assert!(sent.iter().all(|request| request == &[0, 0]));
+        drop(sent);
+        let timeouts = n.s.timeouts.lock().unwrap();
+        assert_eq!(
+            *timeouts,
+            vec![
+                Duration::from_millis(250),
+                Duration::from_millis(500),
+                Duration::from_secs(1)
+            ]
+        );
+    }
+
+    #[cfg(all(feature = "tokio", feature = "async-std"))]
+    #[test]
+    fn test_connect_backends() {
+        // neither backend hides the other
+        let _tokio = connect::<tokio::net::UdpSocket>;
+        let _async_std = connect::<async_std::net::UdpSocket>;
+    }
+
+    #[test]
+    fn test_jitter() {
+        let mut n = mock_natpmp(Vec::new());
+        n.set_retransmission_profile(RetransmissionProfile::fast_fail());
+        assert_eq!(n.set_jitter(1.5), Err(Error::NATPMP_ERR_INVALIDARGS));
+        n.set_jitter(0.5).unwrap();
+        assert_eq!(n.jitter(), 0.5);
+        futures_lite::future::block_on(n.send_public_address_request()).unwrap();
+        let r = futures_lite::future::block_on(n.read_response_or_retry());
+        assert_eq!(r, Err(Error::NATPMP_ERR_RECVFROM));
+        let timeouts = n.s.timeouts.lock().unwrap();
+        assert_eq!(timeouts.len(), 3);
+        for (attempt, timeout) in (1..).zip(timeouts.iter()) {
+            let delay = n.retransmission_profile().delay(attempt);
+            assert!(*timeout >= delay / 2 && *timeout <= delay * 3 / 2);
+        }
+    }
+
+    #[test]
+    fn test_jitter_seed() {
+        let mut n = mock_natpmp(Vec::new());
+        let profile = RetransmissionProfile::fast_fail();
+        n.set_retransmission_profile(profile);
+        n.set_jitter(0.5).unwrap();
+        n.set_jitter_seed(6886);
+        futures_lite::future::block_on(n.send_public_address_request()).unwrap();
+        let r = futures_lite::future::block_on(n.read_response_or_retry());
+        assert_eq!(r, Err(Error::NATPMP_ERR_RECVFROM));
+        // the timeouts are those of the seeded source, one draw per attempt
+        let mut rng = fastrand::Rng::with_seed(6886);
+        let expected: Vec<Duration> = (1..=profile.max_attempts())
+            .map(|attempt| {
+                profile
+                    .delay(attempt)
+                    .mul_f32(1.0 + 0.5 * (rng.f32() * 2.0 - 1.0))
+            })
+            .collect();
+        assert_eq!(*n.s.timeouts.lock().unwrap(), expected);
+        assert_ne!(expected[0], profile.delay(1));
+    }
+
+    #[test]
+    fn test_definitive_error() {
+        for (kind, error) in [
+            (
+                io::ErrorKind::ConnectionRefused,
+                Error::NATPMP_ERR_NOGATEWAYSUPPORT,
+            ),
+            (
+                io::ErrorKind::HostUnreachable,
+                Error::NATPMP_ERR_NETWORKFAILURE,
+            ),
+            (
+                io::ErrorKind::NetworkUnreachable,
+                Error::NATPMP_ERR_NETWORKFAILURE,
+            ),
+        ] {
+            let n = mock_natpmp(vec![Err(kind.into()), Ok(GATEWAY_RESPONSE.to_vec())]);
+            futures_lite::future::block_on(n.send_public_address_request()).unwrap();
+            let r = futures_lite::future::block_on(n.read_response_or_retry());
+            assert_eq!(r, Err(error));
+            // neither retransmitted nor waiting for the next datagram
+            assert_eq!(n.s.sent.lock().unwrap().len(), 1);
+            assert_eq!(n.s.recv.lock().unwrap().len(), 1);
+        }
+        // transient, retransmitted
+        let n = mock_natpmp(vec![
+            Err(io::ErrorKind::WouldBlock.into()),
+            Ok(GATEWAY_RESPONSE.to_vec()),
+        ]);
+        futures_lite::future::block_on(n.send_public_address_request()).unwrap();
+        let r = futures_lite::future::block_on(n.read_response_or_retry());
+        assert!(matches!(r, Ok(Response::Gateway(_))));
+        assert_eq!(n.s.sent.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_refused_after_send() {
+        // the send succeeded, the icmp port unreachable only shows up on the receive
+        let n = mock_natpmp(vec![Err(io::ErrorKind::ConnectionRefused.into())]);
+        let start = Instant::now();
+        let r = futures_lite::future::block_on(n.map_port(Protocol::UDP, 7000, 7000, 60));
+        assert_eq!(r, Err(Error::NATPMP_ERR_NOGATEWAYSUPPORT));
+        assert!(start.elapsed() < Duration::from_millis(NATPMP_MIN_WAIT));
+        assert_eq!(n.s.sent.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_drain_responses() {
+        let mapping = crate::tests::mapping_response(&[0, 2, 0, 0, 0x1f, 0x90], 8080, 60);
+        let n = mock_natpmp(vec![
+            Ok(GATEWAY_RESPONSE.to_vec()),
+            Ok(b"garbage".to_vec()),
+            Err(io::ErrorKind::Interrupted.into()),
+            Ok(mapping),
+        ]);
+        let timeout = Duration::from_millis(10);
+        let responses = futures_lite::future::block_on(n.drain_responses(timeout));
+        assert_eq!(responses.len(), 3);
+        assert!(matches!(responses[0], Ok(Response::Gateway(_))));
+        assert_eq!(responses[1], Err(Error::NATPMP_ERR_UNSUPPORTEDVERSION));
+        match &responses[2] {
+            Ok(Response::TCP(m)) => assert_eq!(m.private_port(), 8080),
+            _ => panic!("Not a tcp mapping response"),
+        }
+        assert!(n.s.sent.lock().unwrap().is_empty());
+
+        let n = mock_natpmp(vec![
+            Ok(GATEWAY_RESPONSE.to_vec()),
+            Err(io::ErrorKind::ConnectionRefused.into()),
+            Ok(GATEWAY_RESPONSE.to_vec()),
+        ]);
+        let responses = futures_lite::future::block_on(n.drain_responses(timeout));
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[1], Err(Error::NATPMP_ERR_NOGATEWAYSUPPORT));
+        assert!(
+            futures_lite::future::block_on(mock_natpmp(Vec::new()).drain_responses(timeout))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_looped_back_request() {
+        let n = mock_natpmp(vec![Ok(vec![0, 0])]);
+        let r = futures_lite::future::block_on(n.read_response_or_retry());
+        assert_eq!(r, Err(Error::NATPMP_ERR_NOTARESPONSE));
+    }
+
+    #[test]
+    fn test_ping() {
+        let n = mock_natpmp(vec![Ok(b"garbage".to_vec()), Ok(GATEWAY_RESPONSE.to_vec())]);
+        let rtt = futures_lite::future::block_on(n.ping()).unwrap();
+        assert!(rtt < Duration::from_secs(1));
+        // not retransmitted
+        let n = mock_natpmp(Vec::new());
+        assert_eq!(
+            futures_lite::future::block_on(n.ping()),
+            Err(Error::NATPMP_ERR_RECVFROM)
+        );
+        assert_eq!(n.s.sent.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_garbage_only() {
+        let n = mock_natpmp(vec![Ok(b"garbage".to_vec())]);
+        let r = futures_lite::future::block_on(n.read_response_or_retry());
+        assert_eq!(r, Err(Error::NATPMP_ERR_UNSUPPORTEDVERSION));
+    }
+
+    #[test]
+    fn test_concurrent_exchanges() {
+        let s = MockSocket {
+            respond: Some(|request| crate::tests::mapping_response(request, 1, 60)),
+            ..MockSocket::default()
+        };
+        let n = new_natpmp_async_with(s, Ipv4Addr::new(192, 168, 0, 1));
+        let (first, second) = futures_lite::future::block_on(futures_lite::future::zip(
+            n.map_port(Protocol::TCP, 1000, 1000, 60),
+            n.map_port(Protocol::TCP, 2000, 2000, 60),
+        ));
+        // interleaved, each would read the response to the other
+        assert_eq!(first.unwrap().private_port(), 1000);
+        assert_eq!(second.unwrap().private_port(), 2000);
+    }
 
-        Err(Error::NATPMP_ERR_RECVFROM)
+    #[test]
+    fn test_set_gateway() {
+        let mut n = mock_natpmp(Vec::new());
+        let other = Ipv4Addr::new(10, 0, 0, 1);
+        futures_lite::future::block_on(n.set_gateway(other)).unwrap();
+        assert_eq!(n.gateway(), &other);
+        assert_eq!(*n.s.connected.lock().unwrap(), vec!["10.0.0.1:5351"]);
+        // unchanged if the socket could not connect
+        assert_eq!(
+            futures_lite::future::block_on(n.set_gateway(Ipv4Addr::UNSPECIFIED)),
+            Err(Error::NATPMP_ERR_CONNECTERR)
+        );
+        assert_eq!(n.gateway(), &other);
     }
 }
@@ -0,0 +1,229 @@
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::{Error, Natpmp, Result, RetransmissionProfile, SocketMode};
+
+/// Everything needed to create an identical [`Natpmp`](struct.Natpmp.html) client.
+///
+/// The text form, one `key=value` pair per setting separated by spaces, can be stored in a
+/// config file or pasted in a bug report and parsed back. Durations are in milliseconds.
+/// Only `gateway` is required when parsing, other settings default to those of
+/// [`Natpmp::new_with`](struct.Natpmp.html#method.new_with).
+///
+/// # Examples
+/// ```
+/// use natpmp::*;
+///
+/// # fn main() -> Result<()> {
+/// let n = Natpmp::new_with("192.168.0.1".parse().unwrap())?;
+/// let config = n.config();
+/// assert_eq!(
+///     config.to_string(),
+///     "gateway=192.168.0.1 local_port=0 mode=connected initial_ms=250 multiplier=2 \
+///      max_attempts=9 max_interval_ms=64000 jitter=0 drain_before_request=false"
+/// );
+/// assert_eq!(config.to_string().parse::<ClientConfig>()?, config);
+/// let copy = Natpmp::new_with_config(&config)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ClientConfig {
+    pub gateway: Ipv4Addr,
+    /// Local port to bind, 0 lets the system pick one.
+    pub local_port: u16,
+    pub mode: SocketMode,
+    pub profile: RetransmissionProfile,
+    /// See [`Natpmp::set_jitter`](struct.Natpmp.html#method.set_jitter).
+    pub jitter: f32,
+    /// See [`Natpmp::set_jitter_seed`](struct.Natpmp.html#method.set_jitter_seed), `None` to
+    /// seed randomly. Only written out in the text form when set.
+    pub jitter_seed: Option<u64>,
+    /// See [`Natpmp::set_drain_before_request`](struct.Natpmp.html#method.set_drain_before_request).
+    pub drain_before_request: bool,
+}
+
+impl ClientConfig {
+    /// Configuration of [`Natpmp::new_with(gateway)`](struct.Natpmp.html#method.new_with).
+    pub fn new(gateway: Ipv4Addr) -> ClientConfig {
+        ClientConfig {
+            gateway,
+            local_port: 0,
+            mode: SocketMode::Connected,
+            profile: RetransmissionProfile::rfc_default(),
+            jitter: 0.0,
+            jitter_seed: None,
+            drain_before_request: false,
+        }
+    }
+}
+
+impl fmt::Display for ClientConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = match self.mode {
+            SocketMode::Connected => "connected",
+            SocketMode::Unconnected => "unconnected",
+        };
+        write!(
+            f,
+            "gateway={} local_port={} mode={} initial_ms={} multiplier={} max_attempts={} \
+             max_interval_ms={} jitter={} drain_before_request={}",
+            self.gateway,
+            self.local_port,
+            mode,
+            self.profile.initial().as_millis(),
+            self.profile.multiplier(),
+            self.profile.max_attempts(),
+            self.profile.max_interval().as_millis(),
+            self.jitter,
+            self.drain_before_request
+        )?;
+        if let Some(seed) = self.jitter_seed {
+            write!(f, " jitter_seed={}", seed)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ClientConfig {
+    type Err = Error;
+
+    /// Parse the text form, see [`ClientConfig`](struct.ClientConfig.html).
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_INVALIDARGS`](enum.Error.html#variant.NATPMP_ERR_INVALIDARGS) for
+    ///   a missing gateway, an unknown key or an invalid value
+    fn from_str(s: &str) -> Result<ClientConfig> {
+        fn value<T: FromStr>(value: &str) -> Result<T> {
+            value.parse().map_err(|_| Error::NATPMP_ERR_INVALIDARGS)
+        }
+
+        let rfc = RetransmissionProfile::rfc_default();
+        let mut config = ClientConfig::new(Ipv4Addr::UNSPECIFIED);
+        let mut gateway = None;
+        let mut initial = rfc.initial();
+        let mut multiplier = rfc.multiplier();
+        let mut max_attempts = rfc.max_attempts();
+        let mut max_interval = rfc.max_interval();
+        for pair in s.split_whitespace() {
+            let (key, v) = pair.split_once('=').ok_or(Error::NATPMP_ERR_INVALIDARGS)?;
+            match key {
+                "gateway" => gateway = Some(value(v)?),
+                "local_port" => config.local_port = value(v)?,
+                "mode" => {
+                    config.mode = match v {
+                        "connected" => SocketMode::Connected,
+                        "unconnected" => SocketMode::Unconnected,
+                        _ => return Err(Error::NATPMP_ERR_INVALIDARGS),
+                    }
+                }
+                "initial_ms" => initial = Duration::from_millis(value(v)?),
+                "multiplier" => multiplier = value(v)?,
+                "max_attempts" => max_attempts = value(v)?,
+                "max_interval_ms" => max_interval = Duration::from_millis(value(v)?),
+                "jitter" => config.jitter = value(v)?,
+                "jitter_seed" => config.jitter_seed = Some(value(v)?),
+                "drain_before_request" => config.drain_before_request = value(v)?,
+                _ => return Err(Error::NATPMP_ERR_INVALIDARGS),
+            }
+        }
+        if !(0.0..=1.0).contains(&config.jitter) {
+            return Err(Error::NATPMP_ERR_INVALIDARGS);
+        }
+        config.gateway = gateway.ok_or(Error::NATPMP_ERR_INVALIDARGS)?;
+        config.profile =
+            RetransmissionProfile::new(initial, multiplier, max_attempts, max_interval)?;
+        Ok(config)
+    }
+}
+
+impl Natpmp {
+    /// Create a NAT-PMP object from a configuration, see
+    /// [`ClientConfig`](struct.ClientConfig.html).
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_INVALIDARGS`](enum.Error.html#variant.NATPMP_ERR_INVALIDARGS) if
+    ///   the jitter is not within `0.0..=1.0`
+    /// * See [`Natpmp::new_with_mode`](struct.Natpmp.html#method.new_with_mode).
+    pub fn new_with_config(config: &ClientConfig) -> Result<Natpmp> {
+        let mut n = Natpmp::new_with_mode(config.gateway, config.local_port, config.mode)?;
+        n.set_retransmission_profile(config.profile);
+        n.set_jitter(config.jitter)?;
+        if let Some(seed) = config.jitter_seed {
+            n.set_jitter_seed(seed);
+        }
+        n.set_drain_before_request(config.drain_before_request);
+        Ok(n)
+    }
+
+    /// Current configuration, creating an identical client with
+    /// [`Natpmp::new_with_config`](struct.Natpmp.html#method.new_with_config).
+    ///
+    /// The local port is the one asked for at construction, 0 if the system picked it.
+    pub fn config(&self) -> ClientConfig {
+        ClientConfig {
+            gateway: self.gateway,
+            local_port: self.local_port,
+            mode: self.mode,
+            profile: self.profile,
+            jitter: self.jitter(),
+            jitter_seed: self.jitter.seed(),
+            drain_before_request: self.drain_before_request,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() -> Result<()> {
+        let gateway = Ipv4Addr::new(192, 168, 0, 1);
+        let mut n = Natpmp::new_with_mode(gateway, 0, SocketMode::Unconnected)?;
+        n.set_retransmission_profile(RetransmissionProfile::new(
+            Duration::from_millis(500),
+            1.5,
+            4,
+            Duration::from_secs(2),
+        )?);
+        n.set_jitter(0.25)?;
+        n.set_jitter_seed(6886);
+        n.set_drain_before_request(true);
+        let config = n.config();
+        let text = config.to_string();
+        assert_eq!(
+            text,
+            "gateway=192.168.0.1 local_port=0 mode=unconnected initial_ms=500 multiplier=1.5 \
+             max_attempts=4 max_interval_ms=2000 jitter=0.25 drain_before_request=true \
+             jitter_seed=6886"
+        );
+        assert_eq!(text.parse::<ClientConfig>()?, config);
+        assert_eq!(Natpmp::new_with_config(&config)?.config(), config);
+
+        assert_eq!(
+            "gateway=192.168.0.1".parse::<ClientConfig>()?,
+            ClientConfig::new(gateway)
+        );
+        for invalid in [
+            "",
+            "local_port=1",
+            "gateway=192.168.0.1 port=1",
+            "gateway=192.168.0.1 mode=other",
+            "gateway=192.168.0.1 jitter=2",
+            "gateway=192.168.0.1 jitter_seed=-1",
+            "gateway=192.168.0.1 max_attempts=0",
+            "gateway=192.168.0.1 local_port",
+        ] {
+            assert_eq!(
+                invalid.parse::<ClientConfig>(),
+                Err(Error::NATPMP_ERR_INVALIDARGS),
+                "{}",
+                invalid
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+use std::net::SocketAddrV4;
+
+use crate::{decode_request, decode_response, GatewayResponse, RequestKind, Response, Result};
+
+/// A datagram classified by a [`Decoder`](struct.Decoder.html).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum DecodedPacket {
+    /// Request sent by a client.
+    Request {
+        source: SocketAddrV4,
+        request: RequestKind,
+    },
+    /// Response of a gateway. Mapping responses are never matched against the requests
+    /// fed before, so this may answer no request at all.
+    Response {
+        source: SocketAddrV4,
+        response: Response,
+    },
+    /// Public address announced by a gateway without being requested.
+    Announcement {
+        source: SocketAddrV4,
+        announcement: GatewayResponse,
+    },
+}
+
+/// Push-style decoder of captured NAT-PMP traffic, for proxies and analyzers.
+///
+/// Each datagram is decoded with [`decode_request`](fn.decode_request.html) or
+/// [`decode_response`](fn.decode_response.html), depending on its opcode. A public address
+/// response answering no public address request fed before is classified as an
+/// announcement, so both directions of the traffic should be fed in order.
+///
+/// Only public address responses are matched to requests: the state is a counter of
+/// outstanding public address requests, and datagrams are decoded without allocating. A
+/// mapping response is always a [`DecodedPacket::Response`](enum.DecodedPacket.html),
+/// whether or not its mapping was requested.
+///
+/// # Examples
+/// ```
+/// use natpmp::*;
+///
+/// # fn main() -> Result<()> {
+/// let mut decoder = Decoder::new();
+/// let gateway = "192.168.0.1:5351".parse().unwrap();
+/// let announcement = [0, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 7];
+/// assert!(matches!(
+///     decoder.feed(gateway, &announcement)?,
+///     DecodedPacket::Announcement { .. }
+/// ));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Decoder {
+    pending_public_address: u32,
+}
+
+impl Decoder {
+    /// Create a decoder without outstanding requests.
+    pub fn new() -> Decoder {
+        Decoder::default()
+    }
+
+    /// Decode a datagram sent from `source`.
+    ///
+    /// # Errors
+    /// See [`decode_request`](fn.decode_request.html) for opcodes below 128,
+    /// [`decode_response`](fn.decode_response.html) otherwise.
+    pub fn feed(&mut self, source: SocketAddrV4, buf: &[u8]) -> Result<DecodedPacket> {
+        if buf.get(1).is_none_or(|opcode| *opcode < 128) {
+            let request = decode_request(buf)?;
+            if request == RequestKind::PublicAddress {
+                self.pending_public_address = self.pending_public_address.saturating_add(1);
+            }
+            return Ok(DecodedPacket::Request { source, request });
+        }
+        let response = decode_response(buf);
+        // an error result code answers a request too
+        if buf[1] == 128 && self.pending_public_address > 0 {
+            self.pending_public_address -= 1;
+        } else if let Ok(Response::Gateway(announcement)) = response {
+            return Ok(DecodedPacket::Announcement {
+                source,
+                announcement,
+            });
+        }
+        Ok(DecodedPacket::Response {
+            source,
+            response: response?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Error, Natpmp, Protocol};
+
+    #[test]
+    fn test_decoder() -> Result<()> {
+        let client = "192.168.0.10:40000".parse().unwrap();
+        let gateway = "192.168.0.1:5351".parse().unwrap();
+        let gateway_response = [0u8, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 7];
+        let mut decoder = Decoder::new();
+        assert_eq!(
+            decoder.feed(client, &Natpmp::build_public_address_request())?,
+            DecodedPacket::Request {
+                source: client,
+                request: RequestKind::PublicAddress
+            }
+        );
+        assert!(matches!(
+            decoder.feed(gateway, &gateway_response)?,
+            DecodedPacket::Response {
+                response: Response::Gateway(_),
+                ..
+            }
+        ));
+        // unrequested
+        assert!(matches!(
+            decoder.feed(gateway, &gateway_response)?,
+            DecodedPacket::Announcement { .. }
+        ));
+        let request = Natpmp::build_mapping_request(Protocol::TCP, 8080, 8080, 60)?;
+        assert!(matches!(
+            decoder.feed(client, &request)?,
+            DecodedPacket::Request { .. }
+        ));
+        let mut response = crate::tests::mapping_response(&request, 8080, 60);
+        assert!(matches!(
+            decoder.feed(gateway, &response)?,
+            DecodedPacket::Response {
+                response: Response::TCP(_),
+                ..
+            }
+        ));
+        // mapping responses are not matched to requests
+        assert!(matches!(
+            decoder.feed(gateway, &response)?,
+            DecodedPacket::Response {
+                response: Response::TCP(_),
+                ..
+            }
+        ));
+        response[3] = 2;
+        assert_eq!(
+            decoder.feed(gateway, &response),
+            Err(Error::NATPMP_ERR_MAPPINGDISABLED)
+        );
+        assert_eq!(
+            decoder.feed(client, &[]),
+            Err(Error::NATPMP_ERR_MALFORMEDREQUEST)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,472 @@
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::{
+    decode_response, Error, MappingResponse, Natpmp, Protocol, Response, Result, NATPMP_MIN_WAIT,
+    NATPMP_PORT, NATPMP_RECV_BUFFER_SIZE,
+};
+
+/// How long diagnose listens for replies.
+const NATPMP_DIAGNOSE_WINDOW: Duration = Duration::from_millis(1000);
+
+/// Lifetime of the mappings created to probe the gateway.
+const NATPMP_PROBE_LIFETIME: u32 = 2;
+
+/// Whether `e` is a failure of the client's own socket, which no probe can get past.
+fn is_socket_error(e: Error) -> bool {
+    matches!(
+        e,
+        Error::NATPMP_ERR_SOCKETERROR | Error::NATPMP_ERR_FCNTLERROR | Error::NATPMP_ERR_SENDERR
+    )
+}
+
+/// Whether a public address looks like it is behind another NAT.
+///
+/// True for the shared address space of carrier-grade NATs (rfc6598) and private networks
+/// (rfc1918): an address a NAT-PMP gateway reports there is not reachable from the internet.
+///
+/// # Examples
+/// ```
+/// use natpmp::*;
+///
+/// assert!(appears_cgnat(&"100.64.1.2".parse().unwrap()));
+/// assert!(!appears_cgnat(&"203.0.113.7".parse().unwrap()));
+/// ```
+pub fn appears_cgnat(public_address: &Ipv4Addr) -> bool {
+    let octets = public_address.octets();
+    let shared = octets[0] == 100 && (octets[1] & 0xc0) == 64;
+    shared || public_address.is_private()
+}
+
+/// What the gateway supports, see [`Natpmp::capabilities`](struct.Natpmp.html#method.capabilities).
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub struct Capabilities {
+    supports_tcp: bool,
+    supports_udp: bool,
+    external_address: Option<Ipv4Addr>,
+    appears_cgnat: bool,
+}
+
+impl Capabilities {
+    /// Whether the gateway created a TCP mapping.
+    pub fn supports_tcp(&self) -> bool {
+        self.supports_tcp
+    }
+
+    /// Whether the gateway created a UDP mapping.
+    pub fn supports_udp(&self) -> bool {
+        self.supports_udp
+    }
+
+    /// Public address of the gateway, `None` if the request failed.
+    pub fn external_address(&self) -> Option<&Ipv4Addr> {
+        self.external_address.as_ref()
+    }
+
+    /// Whether the public address appears to be behind another NAT, see [`appears_cgnat`](fn.appears_cgnat.html).
+    pub fn appears_cgnat(&self) -> bool {
+        self.appears_cgnat
+    }
+}
+
+/// A datagram heard while diagnosing.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DiagnosticDatagram {
+    source: SocketAddr,
+    len: usize,
+    response: Result<Response>,
+}
+
+impl DiagnosticDatagram {
+    /// Address the datagram was sent from.
+    pub fn source(&self) -> &SocketAddr {
+        &self.source
+    }
+
+    /// Datagram length in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the datagram was empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The datagram decoded as a NAT-PMP response.
+    pub fn response(&self) -> &Result<Response> {
+        &self.response
+    }
+}
+
+/// What [`Natpmp::diagnose`](struct.Natpmp.html#method.diagnose) heard.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DiagnosticReport {
+    gateway: Ipv4Addr,
+    datagrams: Vec<DiagnosticDatagram>,
+}
+
+impl DiagnosticReport {
+    /// Gateway the requests were sent to.
+    pub fn gateway(&self) -> &Ipv4Addr {
+        &self.gateway
+    }
+
+    /// Every datagram received, wherever it came from.
+    pub fn datagrams(&self) -> &[DiagnosticDatagram] {
+        &self.datagrams
+    }
+
+    /// Whether the gateway answered from its own address and the NAT-PMP port.
+    pub fn gateway_replied(&self) -> bool {
+        let gateway = SocketAddr::from((self.gateway, NATPMP_PORT));
+        self.datagrams
+            .iter()
+            .any(|d| d.source == gateway && d.response.is_ok())
+    }
+
+    /// Whether valid responses only came from another address than the gateway's.
+    ///
+    /// Replies from another address are dropped by a connected client, which then times out.
+    pub fn asymmetric_routing(&self) -> bool {
+        !self.gateway_replied() && self.datagrams.iter().any(|d| d.response.is_ok())
+    }
+}
+
+/// Everything [`Natpmp::full_diagnose`](struct.Natpmp.html#method.full_diagnose) found out.
+///
+/// Its `Display` output is meant to be pasted into bug reports.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FullDiagnosticReport {
+    gateway: Ipv4Addr,
+    capabilities: Capabilities,
+    rtt: Option<Duration>,
+    mapping_probe: Result<MappingResponse>,
+}
+
+impl FullDiagnosticReport {
+    /// Gateway the requests were sent to.
+    pub fn gateway(&self) -> &Ipv4Addr {
+        &self.gateway
+    }
+
+    /// What the gateway supports.
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    /// Time the public address request took, including retransmissions, `None` if it failed.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// Result of the TCP probe mapping, destroyed since.
+    pub fn mapping_probe(&self) -> &Result<MappingResponse> {
+        &self.mapping_probe
+    }
+}
+
+impl fmt::Display for FullDiagnosticReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "gateway: {}", self.gateway)?;
+        match self.capabilities.external_address() {
+            Some(addr) => writeln!(
+                f,
+                "external address: {} (appears cgnat: {})",
+                addr,
+                self.capabilities.appears_cgnat()
+            )?,
+            None => writeln!(f, "external address: unknown")?,
+        }
+        match self.rtt {
+            Some(rtt) => writeln!(f, "rtt: {:?}", rtt)?,
+            None => writeln!(f, "rtt: unknown")?,
+        }
+        match &self.mapping_probe {
+            Ok(m) => writeln!(
+                f,
+                "tcp mapping: public port {}, lifetime {}s",
+                m.public_port(),
+                m.lifetime().as_secs()
+            )?,
+            Err(e) => writeln!(f, "tcp mapping: {}", e)?,
+        }
+        write!(f, "udp mapping: {}", self.capabilities.supports_udp())
+    }
+}
+
+impl Natpmp {
+    /// Send public address requests to the gateway and report every datagram heard back.
+    ///
+    /// Uses a separate unconnected socket, so replies from an unexpected source address or
+    /// port, which the client itself silently drops, are reported too. Listens for about a
+    /// second, retransmitting like a regular request.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR)
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let mut n = Natpmp::new()?;
+    /// let report = n.diagnose()?;
+    /// for d in report.datagrams() {
+    ///     println!("{} bytes from {}: {:?}", d.len(), d.source(), d.response());
+    /// }
+    /// if report.asymmetric_routing() {
+    ///     println!("the gateway replies from another address");
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn diagnose(&mut self) -> Result<DiagnosticReport> {
+        let s = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+            .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?;
+        let gateway = SocketAddrV4::new(self.gateway, NATPMP_PORT);
+        let request = Natpmp::build_public_address_request();
+
+        let start = Instant::now();
+        let deadline = start + NATPMP_DIAGNOSE_WINDOW;
+        let mut next_send = start;
+        let mut delay = Duration::from_millis(NATPMP_MIN_WAIT);
+        let mut datagrams = Vec::new();
+        let mut buf = [0u8; NATPMP_RECV_BUFFER_SIZE];
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            if now >= next_send {
+                s.send_to(&request, gateway)
+                    .map_err(|_| Error::NATPMP_ERR_SENDERR)?;
+                next_send = now + delay;
+                delay *= 2;
+            }
+            let timeout = next_send.min(deadline).saturating_duration_since(now);
+            if timeout == Duration::from_millis(0) || s.set_read_timeout(Some(timeout)).is_err() {
+                continue;
+            }
+            if let Ok((n, source)) = s.recv_from(&mut buf) {
+                datagrams.push(DiagnosticDatagram {
+                    source,
+                    len: n,
+                    response: decode_response(&buf[..n]),
+                });
+            }
+        }
+        Ok(DiagnosticReport {
+            gateway: self.gateway,
+            datagrams,
+        })
+    }
+
+    /// Probe what the gateway supports.
+    ///
+    /// Requests the public address, then a short-lived TCP and UDP mapping of the client's
+    /// own local port which are destroyed right away. Probes which fail or time out are
+    /// reported as unsupported, the others are still run: an unknown external address does
+    /// not stop the mapping probes.
+    ///
+    /// # Errors
+    /// Only failures of the client's socket, which no probe can get past:
+    /// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR)
+    /// * [`Error::NATPMP_ERR_FCNTLERROR`](enum.Error.html#variant.NATPMP_ERR_FCNTLERROR)
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR) if the
+    ///   public address request could not be sent
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let mut n = Natpmp::new()?;
+    /// let capabilities = n.capabilities()?;
+    /// if capabilities.appears_cgnat() {
+    ///     println!("mappings will not be reachable from the internet");
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn capabilities(&mut self) -> Result<Capabilities> {
+        self.full_diagnose().map(|report| report.capabilities)
+    }
+
+    /// Probe the gateway like [`capabilities`](struct.Natpmp.html#method.capabilities), also
+    /// timing the public address request and keeping the result of the TCP probe mapping.
+    ///
+    /// # Errors
+    /// See [`capabilities`](struct.Natpmp.html#method.capabilities).
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let mut n = Natpmp::new()?;
+    /// println!("{}", n.full_diagnose()?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn full_diagnose(&mut self) -> Result<FullDiagnosticReport> {
+        let start = Instant::now();
+        let external_address = match self.external_address() {
+            Ok(addr) => Some(addr),
+            Err(e) if is_socket_error(e) => return Err(e),
+            // e.g. timed out, the mappings may still work
+            Err(_) => None,
+        };
+        let rtt = external_address.map(|_| start.elapsed());
+        let port = self.local_addr()?.port();
+        let mut probe = |protocol| {
+            let m = self.map_port(protocol, port, port, NATPMP_PROBE_LIFETIME);
+            if m.is_ok() {
+                let _ = self.destroy_mapping(protocol, port);
+            }
+            m
+        };
+        let mapping_probe = probe(Protocol::TCP);
+        let supports_udp = probe(Protocol::UDP).is_ok();
+        Ok(FullDiagnosticReport {
+            gateway: self.gateway,
+            capabilities: Capabilities {
+                supports_tcp: mapping_probe.is_ok(),
+                supports_udp,
+                external_address,
+                appears_cgnat: external_address.as_ref().is_some_and(appears_cgnat),
+            },
+            rtt,
+            mapping_probe,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::tests::{fake_gateway, mapping_response};
+
+    #[test]
+    fn test_diagnose_asymmetric() -> Result<()> {
+        let gateway = Ipv4Addr::new(127, 0, 0, 5);
+        let g = UdpSocket::bind((gateway, NATPMP_PORT)).unwrap();
+        let other = UdpSocket::bind((gateway, 0)).unwrap();
+        let source = other.local_addr().unwrap();
+        let t = thread::spawn(move || {
+            let mut buf = [0u8; 12];
+            let (_, addr) = g.recv_from(&mut buf).unwrap();
+            // the reply comes from another port
+            other
+                .send_to(&[0, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 7], addr)
+                .unwrap();
+        });
+        let mut n = Natpmp::new_with(gateway)?;
+        let report = n.diagnose()?;
+        t.join().unwrap();
+        assert_eq!(report.gateway(), &gateway);
+        assert_eq!(report.datagrams().len(), 1);
+        assert_eq!(report.datagrams()[0].source(), &source);
+        assert_eq!(report.datagrams()[0].len(), 12);
+        assert!(report.datagrams()[0].response().is_ok());
+        assert!(!report.gateway_replied());
+        assert!(report.asymmetric_routing());
+        Ok(())
+    }
+
+    #[test]
+    fn test_capabilities() -> Result<()> {
+        let gateway = Ipv4Addr::new(127, 0, 0, 7);
+        let g = fake_gateway(gateway, 4, |request| {
+            if request.len() == 2 {
+                return vec![0, 128, 0, 0, 0, 0, 0, 1, 100, 64, 0, 1];
+            }
+            let mut response = mapping_response(request, 1, 2);
+            // no udp
+            if request[1] == 1 {
+                response[3] = 5;
+            }
+            response
+        });
+        let mut n = Natpmp::new_with(gateway)?;
+        let capabilities = n.capabilities()?;
+        assert_eq!(
+            capabilities.external_address(),
+            Some(&Ipv4Addr::new(100, 64, 0, 1))
+        );
+        assert!(capabilities.appears_cgnat());
+        assert!(capabilities.supports_tcp());
+        assert!(!capabilities.supports_udp());
+        let requests = g.join().unwrap();
+        // the tcp probe was destroyed
+        assert_eq!(&requests[2][1..2], &[2]);
+        assert_eq!(&requests[2][8..12], &[0, 0, 0, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_full_diagnose() -> Result<()> {
+        let gateway = Ipv4Addr::new(127, 0, 0, 22);
+        let g = fake_gateway(gateway, 4, |request| {
+            if request.len() == 2 {
+                return vec![0, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 7];
+            }
+            let mut response = mapping_response(request, 1, 2);
+            // no udp
+            if request[1] == 1 {
+                response[3] = 2;
+            }
+            response
+        });
+        let mut n = Natpmp::new_with(gateway)?;
+        let report = n.full_diagnose()?;
+        g.join().unwrap();
+        assert!(report.rtt().is_some());
+        assert_eq!(
+            report.mapping_probe().as_ref().map(|m| m.public_port()),
+            Ok(1)
+        );
+        assert!(report.capabilities().supports_tcp());
+        assert!(!report.capabilities().supports_udp());
+        let text = report.to_string();
+        assert!(text.starts_with("gateway: 127.0.0.22\n"));
+        assert!(text.contains("external address: 203.0.113.7 (appears cgnat: false)\n"));
+        assert!(text.contains("tcp mapping: public port 1, lifetime 2s\n"));
+        assert!(text.ends_with("udp mapping: false"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_full_diagnose_partial() -> Result<()> {
+        let gateway = Ipv4Addr::new(127, 0, 0, 30);
+        let g = UdpSocket::bind((gateway, NATPMP_PORT)).unwrap();
+        // public address requests go unanswered, mappings work
+        let t = thread::spawn(move || {
+            let mut buf = [0u8; 12];
+            let mut answered = 0;
+            while answered < 4 {
+                let (n, addr) = g.recv_from(&mut buf).unwrap();
+                if n == 12 {
+                    g.send_to(&mapping_response(&buf, 1, 2), addr).unwrap();
+                    answered += 1;
+                }
+            }
+        });
+        let mut n = Natpmp::new_with(gateway)?;
+        n.set_retransmission_profile(crate::RetransmissionProfile::fast_fail());
+        let report = n.full_diagnose()?;
+        t.join().unwrap();
+        assert_eq!(report.capabilities().external_address(), None);
+        assert_eq!(report.rtt(), None);
+        assert!(report.capabilities().supports_tcp());
+        assert!(report.capabilities().supports_udp());
+        let text = report.to_string();
+        assert!(text.contains("external address: unknown\n"));
+        assert!(text.contains("tcp mapping: public port 1, lifetime 2s\n"));
+        Ok(())
+    }
+}
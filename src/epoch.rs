@@ -0,0 +1,146 @@
+use std::time::{Duration, Instant};
+
+/// Seconds since the gateway's start of epoch, as received in responses.
+///
+/// Usually the gateway's uptime, reset when it reboots or loses its mappings.
+///
+/// # Examples
+/// ```
+/// use std::time::Duration;
+/// use natpmp::*;
+///
+/// let epoch = Epoch::from(3600);
+/// assert_eq!(epoch.seconds_since_start(), 3600);
+/// assert_eq!(epoch.as_duration(), Duration::from_secs(3600));
+/// ```
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Default)]
+pub struct Epoch(u32);
+
+impl Epoch {
+    /// Raw seconds since start of epoch.
+    pub fn seconds_since_start(&self) -> u32 {
+        self.0
+    }
+
+    /// Time since start of epoch.
+    pub fn as_duration(&self) -> Duration {
+        Duration::from_secs(u64::from(self.0))
+    }
+}
+
+impl From<u32> for Epoch {
+    fn from(seconds: u32) -> Epoch {
+        Epoch(seconds)
+    }
+}
+
+impl From<Epoch> for u32 {
+    fn from(epoch: Epoch) -> u32 {
+        epoch.0
+    }
+}
+
+/// Tracks the gateway's seconds since start of epoch to detect reboots.
+///
+/// Implements the check of rfc6886 section 3.6: a gateway is considered rebooted when a new
+/// epoch is lower than the previous epoch plus 7/8 of the time elapsed on the client, minus
+/// 2 seconds of slack.
+///
+/// # Examples
+/// ```
+/// use natpmp::*;
+///
+/// let mut tracker = EpochTracker::new();
+/// assert_eq!(tracker.observe(1000), false);
+/// // the gateway restarted counting
+/// assert_eq!(tracker.observe(3), true);
+/// ```
+#[derive(Debug, Copy, Clone, Default)]
+pub struct EpochTracker {
+    first: Option<(u32, Instant)>,
+    last: Option<(u32, Instant)>,
+}
+
+impl EpochTracker {
+    /// Create a tracker without any observed epoch.
+    pub fn new() -> EpochTracker {
+        EpochTracker::default()
+    }
+
+    /// Last observed epoch, if any.
+    pub fn last_epoch(&self) -> Option<u32> {
+        self.last.map(|(epoch, _)| epoch)
+    }
+
+    /// Record an epoch received just now, returns `true` if it indicates a gateway reboot.
+    ///
+    /// The first observed epoch only sets the reference and never indicates a reboot.
+    pub fn observe(&mut self, epoch: u32) -> bool {
+        self.observe_at(epoch, Instant::now())
+    }
+
+    pub(crate) fn observe_at(&mut self, epoch: u32, now: Instant) -> bool {
+        let rebooted = match self.last {
+            Some((last, at)) => {
+                let elapsed = now.saturating_duration_since(at);
+                u64::from(epoch) + 2 < u64::from(last) + elapsed.as_secs() * 7 / 8
+            }
+            None => false,
+        };
+        if rebooted || self.first.is_none() {
+            self.first = Some((epoch, now));
+        }
+        self.last = Some((epoch, now));
+        rebooted
+    }
+
+    /// Rate of the gateway's clock relative to the client's, since the first epoch observed
+    /// after the last reboot.
+    ///
+    /// Above 1 the gateway's clock runs fast, so renewals timed by the client should happen a
+    /// bit earlier. `None` until two epochs were observed at different times. Epochs have a
+    /// resolution of one second, so the ratio is only meaningful over long periods.
+    pub fn observed_drift_ratio(&self) -> Option<f64> {
+        let ((first, first_at), (last, last_at)) = (self.first?, self.last?);
+        let elapsed = last_at.saturating_duration_since(first_at).as_secs_f64();
+        if elapsed == 0.0 {
+            return None;
+        }
+        Some(f64::from(last.saturating_sub(first)) / elapsed)
+    }
+
+    /// Forget the observed epoch.
+    pub fn reset(&mut self) {
+        self.first = None;
+        self.last = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_epoch_tracker() {
+        let start = Instant::now();
+        let mut tracker = EpochTracker::new();
+        assert!(!tracker.observe_at(100, start));
+        assert_eq!(tracker.observed_drift_ratio(), None);
+        // epoch advanced with the wall clock
+        assert!(!tracker.observe_at(200, start + Duration::from_secs(100)));
+        // a slow gateway clock is tolerated
+        assert!(!tracker.observe_at(286, start + Duration::from_secs(200)));
+        assert_eq!(tracker.observed_drift_ratio(), Some(0.93));
+        // restarted from 0 after 100 seconds
+        assert!(tracker.observe_at(10, start + Duration::from_secs(300)));
+        assert_eq!(tracker.last_epoch(), Some(10));
+        // the reboot is only reported once
+        assert!(!tracker.observe_at(11, start + Duration::from_secs(301)));
+        // measured again from the reboot, on a fast clock
+        assert!(!tracker.observe_at(21, start + Duration::from_secs(308)));
+        assert_eq!(tracker.observed_drift_ratio(), Some(11.0 / 8.0));
+        tracker.reset();
+        assert_eq!(tracker.observed_drift_ratio(), None);
+        assert_eq!(tracker.last_epoch(), None);
+    }
+}
@@ -5,7 +5,6 @@ use std::fmt;
 /// # Note
 ///
 /// These errors are for compatibility only:
-/// * [`Error::NATPMP_ERR_INVALIDARGS`](enum.Error.html#variant.NATPMP_ERR_INVALIDARGS)
 /// * [`Error::NATPMP_ERR_CLOSEERR`](enum.Error.html#variant.NATPMP_ERR_CLOSEERR)
 /// * [`Error::NATPMP_ERR_GETTIMEOFDAYERR`](enum.Error.html#variant.NATPMP_ERR_GETTIMEOFDAYERR)
 ///
@@ -76,6 +75,67 @@ pub enum Error {
 
     /// Try again
     NATPMP_TRYAGAIN,
+
+    /// Gateway assigned another public port than requested
+    NATPMP_ERR_PUBLICPORTMISMATCH,
+
+    /// Local address already in use
+    NATPMP_ERR_ADDRINUSE,
+
+    /// No IPv4 default route, e.g. on an IPv6-only network
+    NATPMP_ERR_NOIPV4GATEWAY,
+
+    /// Private port 0 in a mapping request, which destroys every mapping
+    NATPMP_ERR_INVALIDPRIVATEPORT,
+
+    /// Received a request where a response was expected, e.g. a looped back request
+    NATPMP_ERR_NOTARESPONSE,
+
+    /// Requested lifetime longer than `u32::MAX` seconds
+    NATPMP_ERR_INVALIDLIFETIME,
+
+    /// Received a datagram too short for the response it claims to be
+    NATPMP_ERR_MALFORMEDRESPONSE,
+
+    /// Gateway refused the requested public port but can map the private port on another one
+    NATPMP_ERR_PUBLICPORTUNAVAILABLE,
+
+    /// Default gateway detection is not implemented for the target platform, see
+    /// [`get_default_gateway`](fn.get_default_gateway.html)
+    NATPMP_ERR_UNSUPPORTEDPLATFORM,
+
+    /// Gateway refused a mapping request, usually port mapping is disabled on the gateway, see
+    /// [`Error::is_mapping_disabled`](enum.Error.html#method.is_mapping_disabled)
+    NATPMP_ERR_MAPPINGDISABLED,
+
+    /// Received a datagram too short for the request it claims to be
+    NATPMP_ERR_MALFORMEDREQUEST,
+}
+
+impl Error {
+    /// Whether the gateway refused a mapping request, result code 2 in a mapping response.
+    ///
+    /// Routers report this when NAT-PMP answers address requests but port mapping is disabled
+    /// in their settings, so the user can be told to enable it. Result code 2 in a public
+    /// address response is still
+    /// [`Error::NATPMP_ERR_NOTAUTHORIZED`](enum.Error.html#variant.NATPMP_ERR_NOTAUTHORIZED).
+    pub fn is_mapping_disabled(&self) -> bool {
+        *self == Error::NATPMP_ERR_MAPPINGDISABLED
+    }
+
+    /// Whether the requested public port is taken while another one could be mapped, see
+    /// [`Natpmp::map_port_strict`](struct.Natpmp.html#method.map_port_strict).
+    pub fn is_port_unavailable(&self) -> bool {
+        matches!(
+            self,
+            Error::NATPMP_ERR_PUBLICPORTMISMATCH | Error::NATPMP_ERR_PUBLICPORTUNAVAILABLE
+        )
+    }
+
+    /// Whether a datagram arrived but could not be decoded, as opposed to no reply.
+    pub fn is_malformed_response(&self) -> bool {
+        *self == Error::NATPMP_ERR_MALFORMEDRESPONSE
+    }
 }
 
 impl fmt::Display for Error {
@@ -108,6 +168,52 @@ impl fmt::Display for Error {
             Error::NATPMP_ERR_NETWORKFAILURE => write!(f, "network failure"),
             Error::NATPMP_ERR_OUTOFRESOURCES => write!(f, "nat-pmp server out of resources"),
             Error::NATPMP_TRYAGAIN => write!(f, "try again"),
+            Error::NATPMP_ERR_PUBLICPORTMISMATCH => {
+                write!(f, "the gateway assigned another public port")
+            }
+            Error::NATPMP_ERR_ADDRINUSE => write!(f, "local address already in use"),
+            Error::NATPMP_ERR_NOIPV4GATEWAY => write!(f, "no ipv4 default gateway"),
+            Error::NATPMP_ERR_INVALIDPRIVATEPORT => write!(f, "invalid private port 0"),
+            Error::NATPMP_ERR_NOTARESPONSE => {
+                write!(f, "received a nat-pmp request instead of a response")
+            }
+            Error::NATPMP_ERR_INVALIDLIFETIME => {
+                write!(f, "lifetime longer than u32::MAX seconds")
+            }
+            Error::NATPMP_ERR_MALFORMEDRESPONSE => write!(f, "malformed nat-pmp response"),
+            Error::NATPMP_ERR_PUBLICPORTUNAVAILABLE => {
+                write!(f, "the requested public port is unavailable")
+            }
+            Error::NATPMP_ERR_UNSUPPORTEDPLATFORM => write!(
+                f,
+                "gateway detection not supported on this platform, pass the gateway explicitly"
+            ),
+            Error::NATPMP_ERR_MAPPINGDISABLED => write!(
+                f,
+                "mapping refused, port mapping may be disabled in the gateway settings"
+            ),
+            Error::NATPMP_ERR_MALFORMEDREQUEST => write!(f, "malformed nat-pmp request"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode_response;
+
+    #[test]
+    fn test_is_mapping_disabled() {
+        // result code 2 in a tcp mapping response
+        let e = decode_response(&[0, 130, 0, 2, 0, 0, 0, 1, 0, 22, 0, 0, 0, 0, 0, 0]).unwrap_err();
+        assert_eq!(e, Error::NATPMP_ERR_MAPPINGDISABLED);
+        assert!(e.is_mapping_disabled());
+        assert!(e.to_string().contains("port mapping may be disabled"));
+
+        // result code 2 in a public address response
+        let e = decode_response(&[0, 128, 0, 2, 0, 0, 0, 1, 0, 0, 0, 0]).unwrap_err();
+        assert_eq!(e, Error::NATPMP_ERR_NOTAUTHORIZED);
+        assert!(!e.is_mapping_disabled());
+        assert_eq!(e.to_string(), "not authorized");
+    }
+}
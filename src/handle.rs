@@ -0,0 +1,195 @@
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::{lifetime_secs, EpochTracker, MappingResponse, Natpmp, Protocol, Result};
+
+/// A mapping granted by the gateway, to renew or destroy it later.
+///
+/// Captures everything needed to manage the mapping without the original request parameters.
+///
+/// # Examples
+/// ```no_run
+/// use natpmp::*;
+///
+/// # fn main() -> Result<()> {
+/// let mut n = Natpmp::new()?;
+/// let (handle, _) = n.map_port_handle(Protocol::TCP, 8080, 8080, 3600)?;
+/// // later
+/// let handle = handle.renew(&mut n)?;
+/// handle.destroy(&mut n)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct MappingHandle {
+    protocol: Protocol,
+    private_port: u16,
+    public_port: u16,
+    lifetime: Duration,
+    epoch: u32,
+    established: SystemTime,
+}
+
+impl MappingHandle {
+    /// Handle of a mapping granted just now.
+    pub fn new(protocol: Protocol, response: &MappingResponse) -> MappingHandle {
+        MappingHandle {
+            protocol,
+            private_port: response.private_port(),
+            public_port: response.public_port(),
+            lifetime: *response.lifetime(),
+            epoch: response.epoch(),
+            established: SystemTime::now(),
+        }
+    }
+
+    /// Handle of a mapping from its stored parts, e.g. to resume it after a restart.
+    ///
+    /// See [`MappingManager::resume`](struct.MappingManager.html#method.resume).
+    pub fn from_parts(
+        protocol: Protocol,
+        private_port: u16,
+        public_port: u16,
+        lifetime: Duration,
+        epoch: u32,
+        established: SystemTime,
+    ) -> MappingHandle {
+        MappingHandle {
+            protocol,
+            private_port,
+            public_port,
+            lifetime,
+            epoch,
+            established,
+        }
+    }
+
+    /// Mapping protocol.
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Private/internal port.
+    pub fn private_port(&self) -> u16 {
+        self.private_port
+    }
+
+    /// Public/external port granted by the gateway.
+    pub fn public_port(&self) -> u16 {
+        self.public_port
+    }
+
+    /// Mapping lifetime granted by the gateway.
+    pub fn lifetime(&self) -> &Duration {
+        &self.lifetime
+    }
+
+    /// Gateway epoch when the mapping was established.
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    /// Wall-clock time the mapping was established.
+    pub fn established(&self) -> SystemTime {
+        self.established
+    }
+
+    /// How long after it was established to renew the mapping, half its granted lifetime.
+    ///
+    /// The lifetime granted by the gateway is used even if it is longer than requested, as
+    /// rfc6886 requires.
+    pub fn renew_after(&self) -> Duration {
+        self.lifetime / 2
+    }
+
+    /// Wall-clock time the mapping expires, unless the gateway reboots before.
+    pub fn expires_at(&self) -> SystemTime {
+        self.established + self.lifetime
+    }
+
+    /// Whether `epoch`, received just now, shows the gateway rebooted since the mapping was
+    /// established, see [`EpochTracker`](struct.EpochTracker.html).
+    pub(crate) fn rebooted_since(&self, epoch: u32) -> bool {
+        let now = Instant::now();
+        let elapsed = self.established.elapsed().unwrap_or_default();
+        let mut tracker = EpochTracker::new();
+        tracker.observe_at(self.epoch, now.checked_sub(elapsed).unwrap_or(now));
+        tracker.observe_at(epoch, now)
+    }
+
+    /// Renew the mapping for its granted lifetime, returning the handle of the renewed mapping.
+    ///
+    /// # Errors
+    /// * See [`lifetime_secs`](fn.lifetime_secs.html) for a handle built from parts.
+    /// * See [`Natpmp::map_port`](struct.Natpmp.html#method.map_port).
+    pub fn renew(&self, client: &mut Natpmp) -> Result<MappingHandle> {
+        let lifetime = lifetime_secs(self.lifetime)?;
+        client
+            .map_port_handle(self.protocol, self.private_port, self.public_port, lifetime)
+            .map(|(handle, _)| handle)
+    }
+
+    /// Destroy the mapping.
+    ///
+    /// # Errors
+    /// See [`Natpmp::destroy_mapping`](struct.Natpmp.html#method.destroy_mapping).
+    pub fn destroy(&self, client: &mut Natpmp) -> Result<()> {
+        client.destroy_mapping(self.protocol, self.private_port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::tests::{fake_gateway, mapping_response};
+
+    #[test]
+    fn test_renew() -> Result<()> {
+        let gateway = Ipv4Addr::new(127, 0, 0, 4);
+        let g = fake_gateway(gateway, 3, |request| mapping_response(request, 6001, 120));
+        let mut n = Natpmp::new_with(gateway)?;
+        let (handle, m) = n.map_port_handle(Protocol::UDP, 6000, 6000, 60)?;
+        assert_eq!(handle.protocol(), Protocol::UDP);
+        assert_eq!(handle.private_port(), m.private_port());
+        assert_eq!(handle.public_port(), 6001);
+        assert_eq!(handle.lifetime(), &Duration::from_secs(120));
+        handle.renew(&mut n)?;
+        handle.destroy(&mut n)?;
+        let requests = g.join().unwrap();
+        // renewed with the granted port and lifetime
+        assert_eq!(
+            requests[1],
+            Natpmp::build_mapping_request(Protocol::UDP, 6000, 6001, 120).unwrap()
+        );
+        assert_eq!(
+            requests[2],
+            Natpmp::build_mapping_request(Protocol::UDP, 6000, 0, 0).unwrap()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_longer_lifetime() -> Result<()> {
+        let gateway = Ipv4Addr::new(127, 0, 0, 14);
+        // granted two hours for one minute requested
+        let g = fake_gateway(gateway, 2, |request| mapping_response(request, 6100, 7200));
+        let mut n = Natpmp::new_with(gateway)?;
+        let received = std::time::Instant::now();
+        let (handle, m) = n.map_port_handle(Protocol::TCP, 6100, 6100, 60)?;
+        assert_eq!(m.renew_at(received), received + Duration::from_secs(3600));
+        assert_eq!(m.expires_at(received), received + Duration::from_secs(7200));
+        assert_eq!(handle.renew_after(), Duration::from_secs(3600));
+        assert_eq!(
+            handle.expires_at(),
+            handle.established() + Duration::from_secs(7200)
+        );
+        // renewed for the granted lifetime
+        handle.renew(&mut n)?;
+        assert_eq!(
+            g.join().unwrap()[1],
+            Natpmp::build_mapping_request(Protocol::TCP, 6100, 6100, 7200).unwrap()
+        );
+        Ok(())
+    }
+}
@@ -2,15 +2,48 @@
 //!
 //! `natpmp` is a NAT-PMP [IETF RFC 6886](https://tools.ietf.org/html/rfc6886) client library in rust.
 //! It is a rust implementation of the c library [natpmp](https://github.com/miniupnp/natpmp).
+//!
+//! # Features
+//!
+//! The blocking [`Natpmp`](struct.Natpmp.html) client and the runtime-agnostic
+//! [`NatpmpAsync`](struct.NatpmpAsync.html) client, generic over
+//! [`AsyncUdpSocket`](trait.AsyncUdpSocket.html), are always available. Backends for async
+//! runtimes are opt-in:
+//!
+//! * `tokio`: `AsyncUdpSocket` for `tokio::net::UdpSocket` and `new_tokio_natpmp*` constructors
+//! * `async-std`: `AsyncUdpSocket` for `async_std::net::UdpSocket` and `new_async_std_natpmp*` constructors
+//!
+//! With either backend, [`connect`](fn.connect.html) sets up a client in one call, the socket
+//! type picking the runtime. Both can be enabled side by side.
+//! * `relay`: [`RelayUdpSocket`](struct.RelayUdpSocket.html), tunnelling datagrams through
+//!   channels to a relay task
+//! * `all`: every backend
+//!
+//! # Malformed input
+//!
+//! No function panics on the bytes received from the network. Datagrams are validated
+//! before any field is read and malformed ones are skipped or reported as an [`Error`](enum.Error.html).
+//! Adversarial tests and the `fuzz/` target check this.
 
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::convert::TryInto;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, UdpSocket};
 use std::ops::Add;
 use std::result;
-use std::time::{Duration, Instant};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 mod asynchronous;
+mod config;
+mod decoder;
+mod diagnose;
+mod epoch;
 mod error;
 mod ffi;
+mod handle;
+mod listener;
+mod manager;
+mod retransmission;
 
 #[cfg(feature = "tokio")]
 mod a_tokio;
@@ -18,8 +51,29 @@ mod a_tokio;
 #[cfg(feature = "async-std")]
 mod a_std;
 
+#[cfg(feature = "relay")]
+mod relay;
+
+pub use crate::asynchronous::*;
+pub use crate::config::*;
+pub use crate::decoder::*;
+pub use crate::diagnose::*;
+pub use crate::epoch::*;
 pub use crate::error::*;
 use crate::ffi::*;
+pub use crate::handle::*;
+pub use crate::listener::*;
+pub use crate::manager::*;
+pub use crate::retransmission::*;
+
+#[cfg(feature = "tokio")]
+pub use crate::a_tokio::*;
+
+#[cfg(feature = "async-std")]
+pub use crate::a_std::*;
+
+#[cfg(feature = "relay")]
+pub use crate::relay::*;
 
 /// NAT-PMP mini wait milli-seconds
 const NATPMP_MIN_WAIT: u64 = 250;
@@ -27,15 +81,54 @@ const NATPMP_MIN_WAIT: u64 = 250;
 /// NAT-PMP max retry
 const NATPMP_MAX_ATTEMPS: u32 = 9;
 
+/// Size of the buffer responses are received into.
+///
+/// Large enough for a PCP datagram (rfc6887 caps them at 1100 bytes), so
+/// over-long responses are never truncated by the read itself.
+const NATPMP_RECV_BUFFER_SIZE: usize = 1100;
+
+/// Size of the version and opcode header every NAT-PMP datagram starts with.
+const NATPMP_HEADER_SIZE: usize = 2;
+
+/// Size of a public address response.
+const NATPMP_GATEWAY_RESPONSE_SIZE: usize = 12;
+
+/// Size of a port mapping response.
+const NATPMP_MAPPING_RESPONSE_SIZE: usize = 16;
+
 /// NAT-PMP server port as defined by rfc6886.
 pub const NATPMP_PORT: u16 = 5351;
 
 /// NAT-PMP result.
 pub type Result<T> = result::Result<T, Error>;
 
+/// Whether `getgateway.c` can detect the default gateway of the target platform, it
+/// compiles to a stub failing every call otherwise.
+const GATEWAY_DETECTION_SUPPORTED: bool = cfg!(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_vendor = "apple",
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "haiku",
+    windows,
+));
+
 /// Get default gateway.
 ///
+/// Detection is implemented for Linux, Android, the BSDs, Apple platforms, Solaris,
+/// illumos, Haiku and Windows. Elsewhere, pass the gateway explicitly, e.g. with
+/// [`Natpmp::new_with`](struct.Natpmp.html#method.new_with).
+///
 /// # Errors
+/// * [`Error::NATPMP_ERR_UNSUPPORTEDPLATFORM`](enum.Error.html#variant.NATPMP_ERR_UNSUPPORTEDPLATFORM)
+///   if detection is not implemented for the target platform
+/// * [`Error::NATPMP_ERR_NOIPV4GATEWAY`](enum.Error.html#variant.NATPMP_ERR_NOIPV4GATEWAY)
+///   if the host has no IPv4 default route, e.g. on an IPv6-only network
 /// * [`Error::NATPMP_ERR_CANNOTGETGATEWAY`](enum.Error.html#variant.NATPMP_ERR_CANNOTGETGATEWAY)
 ///
 /// # Examples
@@ -46,20 +139,333 @@ pub type Result<T> = result::Result<T, Error>;
 /// assert_eq!(r.is_ok(), true);
 /// ```
 pub fn get_default_gateway() -> Result<Ipv4Addr> {
+    if !GATEWAY_DETECTION_SUPPORTED {
+        return Err(Error::NATPMP_ERR_UNSUPPORTEDPLATFORM);
+    }
     let mut addr: u32 = 0;
     let result: i32 = unsafe { getdefaultgateway(&mut addr) };
     if result == 0 {
         addr = u32::from_be(addr); // to native order
         return Ok(Ipv4Addr::from(addr));
     }
+    if !has_ipv4_route() {
+        return Err(Error::NATPMP_ERR_NOIPV4GATEWAY);
+    }
     Err(Error::NATPMP_ERR_CANNOTGETGATEWAY)
 }
 
-fn convert_to<T: Clone>(bytes: &[u8]) -> T {
-    unsafe {
-        let ptr = bytes.as_ptr();
-        (*(ptr as *const T)).clone()
+/// Whether the host can route to a public IPv4 address.
+///
+/// Connecting a UDP socket only looks up the route, nothing is sent.
+fn has_ipv4_route() -> bool {
+    let s = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)) {
+        Ok(s) => s,
+        // cannot tell
+        Err(_) => return true,
+    };
+    // TEST-NET-1, only reachable through a default route
+    match s.connect((Ipv4Addr::new(192, 0, 2, 1), NATPMP_PORT)) {
+        Err(e) => e.kind() != io::ErrorKind::NetworkUnreachable,
+        Ok(_) => true,
+    }
+}
+
+/// The `N` bytes of the field at `at`, failing instead of panicking on a short datagram.
+fn field<const N: usize>(buf: &[u8], at: usize) -> Result<[u8; N]> {
+    buf.get(at..at + N)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(Error::NATPMP_ERR_MALFORMEDRESPONSE)
+}
+
+/// The `N` bytes of the field at `at` of a request datagram.
+fn request_field<const N: usize>(buf: &[u8], at: usize) -> Result<[u8; N]> {
+    field(buf, at).map_err(|_| Error::NATPMP_ERR_MALFORMEDREQUEST)
+}
+
+/// Encode a port mapping request, rfc6886 section 3.3.
+fn encode_mapping_request(
+    protocol: Protocol,
+    private_port: u16,
+    public_port: u16,
+    lifetime: u32,
+) -> [u8; 12] {
+    let mut request = [0_u8; 12];
+    request[1] = match protocol {
+        Protocol::UDP => 1,
+        _ => 2,
+    };
+    request[2] = 0; // reserved
+    request[3] = 0; // reserved
+    request[4..6].copy_from_slice(&private_port.to_be_bytes());
+    request[6..8].copy_from_slice(&public_port.to_be_bytes());
+    request[8..12].copy_from_slice(&lifetime.to_be_bytes());
+    request
+}
+
+fn bind_error(e: io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::AddrInUse => Error::NATPMP_ERR_ADDRINUSE,
+        _ => Error::NATPMP_ERR_SOCKETERROR,
+    }
+}
+
+/// Check `buf` is shaped like a response: version 0, a response opcode and long enough for it.
+///
+/// Returns the size of the response. Datagrams failing this check are not from a NAT-PMP
+/// gateway, as opposed to responses carrying a result code.
+fn check_response_header(buf: &[u8]) -> Result<usize> {
+    let [version, opcode] = field::<NATPMP_HEADER_SIZE>(buf, 0)?;
+    if version != 0 {
+        return Err(Error::NATPMP_ERR_UNSUPPORTEDVERSION);
+    }
+    // a request, e.g. our own looped back or another client's
+    if opcode <= 2 {
+        return Err(Error::NATPMP_ERR_NOTARESPONSE);
+    }
+    if !(128..=130).contains(&opcode) {
+        return Err(Error::NATPMP_ERR_UNSUPPORTEDOPCODE);
+    }
+    // the gateway and mapping responses have different minimum sizes
+    let expected = if opcode == 128 {
+        NATPMP_GATEWAY_RESPONSE_SIZE
+    } else {
+        NATPMP_MAPPING_RESPONSE_SIZE
+    };
+    if buf.len() < expected {
+        return Err(Error::NATPMP_ERR_MALFORMEDRESPONSE);
+    }
+    Ok(expected)
+}
+
+/// Decode a NAT-PMP response datagram.
+///
+/// `buf` must hold exactly the received datagram. Datagrams shorter than the
+/// response they claim to be are rejected, trailing bytes past the standard
+/// fields are ignored, see [`decode_response_with_extra`](fn.decode_response_with_extra.html).
+///
+/// Never panics, whatever `buf` holds: malformed datagrams are reported as errors.
+///
+/// # Errors
+/// * [`Error::NATPMP_ERR_MALFORMEDRESPONSE`](enum.Error.html#variant.NATPMP_ERR_MALFORMEDRESPONSE)
+///   if `buf` is too short
+/// * [`Error::NATPMP_ERR_UNSUPPORTEDVERSION`](enum.Error.html#variant.NATPMP_ERR_UNSUPPORTEDVERSION)
+/// * [`Error::NATPMP_ERR_UNSUPPORTEDOPCODE`](enum.Error.html#variant.NATPMP_ERR_UNSUPPORTEDOPCODE)
+/// * [`Error::NATPMP_ERR_NOTARESPONSE`](enum.Error.html#variant.NATPMP_ERR_NOTARESPONSE) for
+///   a request opcode
+/// * [`Error::NATPMP_ERR_NOTAUTHORIZED`](enum.Error.html#variant.NATPMP_ERR_NOTAUTHORIZED)
+///   for a public address response
+/// * [`Error::NATPMP_ERR_MAPPINGDISABLED`](enum.Error.html#variant.NATPMP_ERR_MAPPINGDISABLED)
+///   for a mapping response
+/// * [`Error::NATPMP_ERR_NETWORKFAILURE`](enum.Error.html#variant.NATPMP_ERR_NETWORKFAILURE)
+/// * [`Error::NATPMP_ERR_OUTOFRESOURCES`](enum.Error.html#variant.NATPMP_ERR_OUTOFRESOURCES)
+/// * [`Error::NATPMP_ERR_UNDEFINEDERROR`](enum.Error.html#variant.NATPMP_ERR_UNDEFINEDERROR)
+///
+/// # Examples
+/// ```
+/// use std::net::Ipv4Addr;
+/// use natpmp::*;
+///
+/// # fn main() -> Result<()> {
+/// let buf = [0, 128, 0, 0, 0, 0, 0, 10, 203, 0, 113, 7];
+/// match decode_response(&buf)? {
+///     Response::Gateway(gr) => assert_eq!(gr.public_address(), &Ipv4Addr::new(203, 0, 113, 7)),
+///     _ => panic!("Not a gateway response"),
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub fn decode_response(buf: &[u8]) -> Result<Response> {
+    decode_response_with_extra(buf).map(|(response, _)| response)
+}
+
+/// Decode a NAT-PMP response datagram, also returning the bytes past its standard fields.
+///
+/// Some gateways append vendor specific data to their responses, which is returned untouched
+/// as the end of `buf`, usually empty.
+///
+/// # Errors
+/// See [`decode_response`](fn.decode_response.html).
+///
+/// # Examples
+/// ```
+/// use natpmp::*;
+///
+/// # fn main() -> Result<()> {
+/// let buf = [0, 128, 0, 0, 0, 0, 0, 10, 203, 0, 113, 7, 0xca, 0xfe];
+/// let (response, extra) = decode_response_with_extra(&buf)?;
+/// assert!(matches!(response, Response::Gateway(_)));
+/// assert_eq!(extra, &[0xca, 0xfe]);
+/// # Ok(())
+/// # }
+/// ```
+pub fn decode_response_with_extra(buf: &[u8]) -> Result<(Response, &[u8])> {
+    let expected = check_response_header(buf)?;
+    let rsp_type = field::<1>(buf, 1)?[0] & 0x7f;
+    // result code
+    let resultcode = u16::from_be_bytes(field(buf, 2)?);
+    if resultcode != 0 {
+        return Err(match resultcode {
+            1 => Error::NATPMP_ERR_UNSUPPORTEDVERSION,
+            2 if rsp_type == 0 => Error::NATPMP_ERR_NOTAUTHORIZED,
+            2 => Error::NATPMP_ERR_MAPPINGDISABLED,
+            3 => Error::NATPMP_ERR_NETWORKFAILURE,
+            4 => Error::NATPMP_ERR_OUTOFRESOURCES,
+            5 => Error::NATPMP_ERR_UNSUPPORTEDOPCODE,
+            _ => Error::NATPMP_ERR_UNDEFINEDERROR,
+        });
+    }
+    // epoch
+    let epoch = u32::from_be_bytes(field(buf, 4)?);
+    let extra = buf.get(expected..).unwrap_or_default();
+    // result
+    let response = match rsp_type {
+        0 => Response::Gateway(GatewayResponse {
+            epoch,
+            public_address: Ipv4Addr::from(field::<4>(buf, 8)?),
+        }),
+        _ => {
+            let private_port = u16::from_be_bytes(field(buf, 8)?);
+            let public_port = u16::from_be_bytes(field(buf, 10)?);
+            let lifetime = u32::from_be_bytes(field(buf, 12)?);
+            let lifetime = Duration::from_secs(u64::from(lifetime));
+            let m = MappingResponse {
+                protocol: if rsp_type == 1 {
+                    Protocol::UDP
+                } else {
+                    Protocol::TCP
+                },
+                epoch,
+                private_port,
+                public_port,
+                lifetime,
+            };
+            if rsp_type == 1 {
+                Response::UDP(m)
+            } else {
+                Response::TCP(m)
+            }
+        }
+    };
+    Ok((response, extra))
+}
+
+/// Decode a NAT-PMP request datagram, the counterpart of
+/// [`Natpmp::build_public_address_request`](struct.Natpmp.html#method.build_public_address_request)
+/// and [`Natpmp::build_mapping_request`](struct.Natpmp.html#method.build_mapping_request).
+///
+/// For gateways, proxies and tools inspecting captured traffic. The reserved bytes and
+/// trailing bytes past the request are ignored. A private port of 0 is only valid for the
+/// destroy-all request of
+/// [`Natpmp::build_destroy_all_request`](struct.Natpmp.html#method.build_destroy_all_request).
+///
+/// Never panics, whatever `buf` holds.
+///
+/// # Errors
+/// * [`Error::NATPMP_ERR_MALFORMEDREQUEST`](enum.Error.html#variant.NATPMP_ERR_MALFORMEDREQUEST)
+///   if `buf` is shorter than the request
+/// * [`Error::NATPMP_ERR_UNSUPPORTEDVERSION`](enum.Error.html#variant.NATPMP_ERR_UNSUPPORTEDVERSION)
+/// * [`Error::NATPMP_ERR_UNSUPPORTEDOPCODE`](enum.Error.html#variant.NATPMP_ERR_UNSUPPORTEDOPCODE),
+///   also for a response
+/// * [`Error::NATPMP_ERR_INVALIDPRIVATEPORT`](enum.Error.html#variant.NATPMP_ERR_INVALIDPRIVATEPORT)
+///   for a private port of 0 with a lifetime
+///
+/// # Examples
+/// ```
+/// use natpmp::*;
+///
+/// # fn main() -> Result<()> {
+/// let request = Natpmp::build_mapping_request(Protocol::TCP, 8080, 80, 3600)?;
+/// assert_eq!(
+///     decode_request(&request)?,
+///     RequestKind::Mapping {
+///         protocol: Protocol::TCP,
+///         private_port: 8080,
+///         public_port: 80,
+///         lifetime: 3600,
+///     }
+/// );
+/// # Ok(())
+/// # }
+/// ```
+pub fn decode_request(buf: &[u8]) -> Result<RequestKind> {
+    let [version, opcode] = request_field::<NATPMP_HEADER_SIZE>(buf, 0)?;
+    if version != 0 {
+        return Err(Error::NATPMP_ERR_UNSUPPORTEDVERSION);
     }
+    let protocol = match opcode {
+        0 => return Ok(RequestKind::PublicAddress),
+        1 => Protocol::UDP,
+        2 => Protocol::TCP,
+        _ => return Err(Error::NATPMP_ERR_UNSUPPORTEDOPCODE),
+    };
+    let private_port = u16::from_be_bytes(request_field(buf, 4)?);
+    let public_port = u16::from_be_bytes(request_field(buf, 6)?);
+    let lifetime = u32::from_be_bytes(request_field(buf, 8)?);
+    if private_port == 0 && lifetime != 0 {
+        return Err(Error::NATPMP_ERR_INVALIDPRIVATEPORT);
+    }
+    Ok(RequestKind::Mapping {
+        protocol,
+        private_port,
+        public_port,
+        lifetime,
+    })
+}
+
+/// Convert a lifetime to the seconds of a request.
+///
+/// NAT-PMP lifetimes are `u32` seconds, about 136 years at most. Fractions of a second are
+/// dropped.
+///
+/// # Errors
+/// * [`Error::NATPMP_ERR_INVALIDLIFETIME`](enum.Error.html#variant.NATPMP_ERR_INVALIDLIFETIME)
+///   if `lifetime` is longer than `u32::MAX` seconds
+///
+/// # Examples
+/// ```
+/// use std::time::Duration;
+/// use natpmp::*;
+///
+/// assert_eq!(lifetime_secs(Duration::from_secs(3600)), Ok(3600));
+/// assert_eq!(
+///     lifetime_secs(Duration::from_secs(1 << 32)),
+///     Err(Error::NATPMP_ERR_INVALIDLIFETIME)
+/// );
+/// ```
+pub fn lifetime_secs(lifetime: Duration) -> Result<u32> {
+    lifetime
+        .as_secs()
+        .try_into()
+        .map_err(|_| Error::NATPMP_ERR_INVALIDLIFETIME)
+}
+
+/// Render a datagram as spaced hex, e.g. for bug reports or logs.
+///
+/// The output of [`decode_request`](fn.decode_request.html) and
+/// [`decode_response`](fn.decode_response.html) can be checked against the same bytes.
+///
+/// # Examples
+/// ```
+/// use natpmp::*;
+///
+/// assert_eq!(to_hex(&Natpmp::build_public_address_request()), "00 00");
+/// ```
+pub fn to_hex(buf: &[u8]) -> String {
+    buf.iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// How a client socket talks to the gateway.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Default)]
+pub enum SocketMode {
+    /// Connect the socket to the gateway, the system drops datagrams from other sources.
+    #[default]
+    Connected,
+    /// Leave the socket unconnected, sending with `send_to` and skipping datagrams which
+    /// were not sent by the gateway. One socket can then talk to several gateways.
+    Unconnected,
 }
 
 /// NAT-PMP mapping protocol.
@@ -69,6 +475,53 @@ pub enum Protocol {
     TCP,
 }
 
+/// A NAT-PMP request, see [`Natpmp::send_request`](struct.Natpmp.html#method.send_request).
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum RequestKind {
+    /// Public address request.
+    PublicAddress,
+    /// Port mapping request.
+    Mapping {
+        protocol: Protocol,
+        private_port: u16,
+        public_port: u16,
+        lifetime: u32,
+    },
+}
+
+impl RequestKind {
+    /// Wire bytes of the request as spaced hex, see [`to_hex`](fn.to_hex.html).
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// let request = RequestKind::Mapping {
+    ///     protocol: Protocol::UDP,
+    ///     private_port: 4020,
+    ///     public_port: 4020,
+    ///     lifetime: 30,
+    /// };
+    /// assert_eq!(request.to_hex(), "00 01 00 00 0f b4 0f b4 00 00 00 1e");
+    /// ```
+    pub fn to_hex(&self) -> String {
+        match *self {
+            RequestKind::PublicAddress => to_hex(&Natpmp::build_public_address_request()),
+            RequestKind::Mapping {
+                protocol,
+                private_port,
+                public_port,
+                lifetime,
+            } => to_hex(&encode_mapping_request(
+                protocol,
+                private_port,
+                public_port,
+                lifetime,
+            )),
+        }
+    }
+}
+
 /// NAT-PMP response type.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 pub enum ResponseType {
@@ -96,11 +549,17 @@ impl GatewayResponse {
     pub fn epoch(&self) -> u32 {
         self.epoch
     }
+
+    /// Time since the gateway's start of epoch, see [`Epoch`](struct.Epoch.html).
+    pub fn uptime(&self) -> Duration {
+        Epoch::from(self.epoch).as_duration()
+    }
 }
 
 /// Mapping response.
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct MappingResponse {
+    protocol: Protocol,
     epoch: u32,
     private_port: u16,
     public_port: u16,
@@ -108,6 +567,11 @@ pub struct MappingResponse {
 }
 
 impl MappingResponse {
+    /// Mapping protocol.
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
     /// Seconds since epoch.
     ///
     /// **Note: May be not accurate.**
@@ -115,6 +579,11 @@ impl MappingResponse {
         self.epoch
     }
 
+    /// Time since the gateway's start of epoch, see [`Epoch`](struct.Epoch.html).
+    pub fn uptime(&self) -> Duration {
+        Epoch::from(self.epoch).as_duration()
+    }
+
     /// Private/internal port.
     pub fn private_port(&self) -> u16 {
         self.private_port
@@ -125,10 +594,81 @@ impl MappingResponse {
         self.public_port
     }
 
-    /// Mapping lifetime.
+    /// Mapping lifetime granted by the gateway.
+    ///
+    /// Gateways may grant another lifetime than requested, some always grant the same one.
+    /// Renewal timing must follow this one, as the helpers of this type do.
     pub fn lifetime(&self) -> &Duration {
         &self.lifetime
     }
+
+    /// Whether the gateway granted the `requested` lifetime, in seconds.
+    pub fn honored_lifetime(&self, requested: u32) -> bool {
+        self.lifetime == Duration::from_secs(u64::from(requested))
+    }
+
+    /// The request refreshing this mapping for `lifetime` seconds.
+    ///
+    /// Requests the granted public port, so the gateway keeps assigning the same one.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let mut n = Natpmp::new()?;
+    /// let m = n.map_port(Protocol::TCP, 8080, 0, 3600)?;
+    /// // later
+    /// n.send_request(m.refresh_request(3600))?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn refresh_request(&self, lifetime: u32) -> RequestKind {
+        RequestKind::Mapping {
+            protocol: self.protocol,
+            private_port: self.private_port,
+            public_port: self.public_port,
+            lifetime,
+        }
+    }
+
+    /// When the mapping expires, for a response received at `received`.
+    ///
+    /// A gateway reboot drops its mappings before they expire, see
+    /// [`EpochTracker`](struct.EpochTracker.html).
+    ///
+    /// # Examples
+    /// ```
+    /// use std::time::{Duration, Instant};
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let buf = [0, 130, 0, 0, 0, 0, 0, 1, 0x1f, 0x90, 0x1f, 0x90, 0, 0, 0x0e, 0x10];
+    /// let received = Instant::now();
+    /// if let Response::TCP(m) = decode_response(&buf)? {
+    ///     assert_eq!(m.expires_at(received), received + Duration::from_secs(3600));
+    ///     assert_eq!(m.renew_at(received), received + Duration::from_secs(1800));
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn expires_at(&self, received: Instant) -> Instant {
+        received + self.lifetime
+    }
+
+    /// Wall-clock time the mapping expires, for a response received at `received`.
+    ///
+    /// See [`expires_at`](struct.MappingResponse.html#method.expires_at).
+    pub fn expires_at_system(&self, received: SystemTime) -> SystemTime {
+        received + self.lifetime
+    }
+
+    /// When to renew the mapping, at half its lifetime as recommended by rfc6886.
+    ///
+    /// See [`expires_at`](struct.MappingResponse.html#method.expires_at).
+    pub fn renew_at(&self, received: Instant) -> Instant {
+        received + self.lifetime / 2
+    }
 }
 
 /// NAT-PMP response.
@@ -166,16 +706,25 @@ pub enum Response {
 pub struct Natpmp {
     s: UdpSocket,
     gateway: Ipv4Addr,
+    local_port: u16,
     has_pending_request: bool,
     pending_request: [u8; 12],
     pending_request_len: usize,
     try_number: u32,
     retry_time: Instant,
+    jitter: Jitter,
+    drain_before_request: bool,
+    mode: SocketMode,
+    profile: RetransmissionProfile,
 }
 
 impl Natpmp {
     /// Create a NAT-PMP object with default gateway.
     ///
+    /// Where the default gateway cannot be detected, see
+    /// [`get_default_gateway`](fn.get_default_gateway.html), use
+    /// [`Natpmp::new_with`](struct.Natpmp.html#method.new_with) instead.
+    ///
     /// # Errors
     /// See [`get_default_gateway`](fn.get_default_gateway.html) and [`Natpmp::new_with`](struct.Natpmp.html#method.new_with).
     ///
@@ -207,27 +756,79 @@ impl Natpmp {
     /// let n = Natpmp::new_with("192.168.0.1".parse().unwrap()).unwrap();
     /// ```
     pub fn new_with(gateway: Ipv4Addr) -> Result<Natpmp> {
-        let s: UdpSocket;
-        if let Ok(udpsock) = UdpSocket::bind("0.0.0.0:0") {
-            s = udpsock;
-        } else {
-            return Err(Error::NATPMP_ERR_SOCKETERROR);
-        }
+        Natpmp::new_with_local_port(gateway, 0)
+    }
+
+    /// Create a NAT-PMP object with a specified gateway, sending from a fixed local port.
+    ///
+    /// A known source port allows a stable egress firewall rule for NAT-PMP traffic. A
+    /// `local_port` of 0 lets the system pick one.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_ADDRINUSE`](enum.Error.html#variant.NATPMP_ERR_ADDRINUSE)
+    /// * See [`Natpmp::new_with`](struct.Natpmp.html#method.new_with).
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// let n = Natpmp::new_with_local_port("192.168.0.1".parse().unwrap(), 15351).unwrap();
+    /// ```
+    pub fn new_with_local_port(gateway: Ipv4Addr, local_port: u16) -> Result<Natpmp> {
+        Natpmp::new_with_mode(gateway, local_port, SocketMode::Connected)
+    }
+
+    /// Create a NAT-PMP object with a specified gateway, local port and socket mode.
+    ///
+    /// With [`SocketMode::Unconnected`](enum.SocketMode.html#variant.Unconnected), requests to
+    /// `gateway` work as usual and other gateways are reached with
+    /// [`send_port_mapping_request_to`](struct.Natpmp.html#method.send_port_mapping_request_to)
+    /// and [`read_response_from`](struct.Natpmp.html#method.read_response_from).
+    ///
+    /// The socket is bound to `local_port` in both modes, so requests leave from that port and
+    /// the system only delivers datagrams addressed to it. Unconnected mode adds no check of
+    /// the reply destination on top, only of its source.
+    ///
+    /// # Errors
+    /// See [`Natpmp::new_with_local_port`](struct.Natpmp.html#method.new_with_local_port).
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use std::time::Duration;
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let n = Natpmp::new_with_mode("192.168.0.1".parse().unwrap(), 0, SocketMode::Unconnected)?;
+    /// for gateway in ["192.168.0.1", "192.168.1.1"] {
+    ///     let gateway = gateway.parse().unwrap();
+    ///     n.send_port_mapping_request_to(gateway, Protocol::TCP, 8080, 8080, 3600)?;
+    ///     let response = n.read_response_from(gateway, Duration::from_secs(1))?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn new_with_mode(gateway: Ipv4Addr, local_port: u16, mode: SocketMode) -> Result<Natpmp> {
+        let s = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, local_port)).map_err(bind_error)?;
         if s.set_nonblocking(true).is_err() {
             return Err(Error::NATPMP_ERR_FCNTLERROR);
         }
         let gateway_sockaddr = SocketAddrV4::new(gateway, NATPMP_PORT);
-        if s.connect(gateway_sockaddr).is_err() {
+        if mode == SocketMode::Connected && s.connect(gateway_sockaddr).is_err() {
             return Err(Error::NATPMP_ERR_CONNECTERR);
         }
         let n = Natpmp {
             s,
             gateway,
+            local_port,
             has_pending_request: false,
             pending_request: [0u8; 12],
             pending_request_len: 0,
             try_number: 0,
             retry_time: Instant::now(),
+            jitter: Jitter::new(),
+            drain_before_request: false,
+            mode,
+            profile: RetransmissionProfile::rfc_default(),
         };
         Ok(n)
     }
@@ -250,86 +851,250 @@ impl Natpmp {
         &self.gateway
     }
 
+    /// Socket mode chosen at construction.
+    pub fn mode(&self) -> SocketMode {
+        self.mode
+    }
+
+    /// Local address the socket is bound to.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR)
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let n = Natpmp::new_with("192.168.0.1".parse().unwrap())?;
+    /// println!("sending from port {}", n.local_addr()?.port());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn local_addr(&self) -> Result<SocketAddrV4> {
+        match self.s.local_addr() {
+            Ok(SocketAddr::V4(addr)) => Ok(addr),
+            _ => Err(Error::NATPMP_ERR_SOCKETERROR),
+        }
+    }
+
     fn send_pending_request(&self) -> Result<()> {
-        if let Ok(n) = self
+        let request = &self.pending_request[0..self.pending_request_len];
+        log::debug!("sending to {}: {}", self.gateway, to_hex(request));
+        if self.mode == SocketMode::Connected {
+            if let Ok(n) = self.s.send(request) {
+                if n == self.pending_request_len {
+                    return Ok(());
+                }
+            }
+            return Err(Error::NATPMP_ERR_SENDERR);
+        }
+        self.send_to(self.gateway, request)
+    }
+
+    fn send_to(&self, gateway: Ipv4Addr, request: &[u8]) -> Result<()> {
+        match self
             .s
-            .send(&self.pending_request[0..self.pending_request_len])
+            .send_to(request, SocketAddrV4::new(gateway, NATPMP_PORT))
         {
-            if n == self.pending_request_len {
-                return Ok(());
-            }
+            Ok(n) if n == request.len() => Ok(()),
+            _ => Err(Error::NATPMP_ERR_SENDERR),
         }
-        Err(Error::NATPMP_ERR_SENDERR)
     }
 
     fn send_natpmp_request(&mut self) -> Result<()> {
+        if self.drain_before_request {
+            self.drain()?;
+        }
         self.has_pending_request = true;
         self.try_number = 1;
         let result = self.send_pending_request();
         self.retry_time = Instant::now();
-        self.retry_time = self.retry_time.add(Duration::from_millis(250));
+        self.retry_time = self
+            .retry_time
+            .add(self.retransmission_delay(self.try_number));
         result
     }
 
-    /// Get timeout duration of the currently pending NAT-PMP request.
+    /// Timeout of the given attempt according to the retransmission profile, with jitter applied.
+    fn retransmission_delay(&mut self, try_number: u32) -> Duration {
+        self.jitter.apply(self.profile.delay(try_number))
+    }
+
+    /// Set how requests are retransmitted, [`RetransmissionProfile::rfc_default`](struct.RetransmissionProfile.html#method.rfc_default)
+    /// by default.
     ///
-    /// # Errors:
-    /// * [`Error::NATPMP_ERR_NOPENDINGREQ`](enum.Error.html#variant.NATPMP_ERR_NOPENDINGREQ)
+    /// Applies from the next request on.
     ///
     /// # Examples
     /// ```
-    /// use std::time::Duration;
     /// use natpmp::*;
     ///
     /// # fn main() -> Result<()> {
-    /// let mut n = Natpmp::new()?;
-    /// n.send_public_address_request()?;
-    /// // do something
-    /// let duration = n.get_natpmp_request_timeout()?;
-    /// if duration <= Duration::from_millis(10) {
-    ///     // read response ...
-    /// }
+    /// let mut n = Natpmp::new_with("192.168.0.1".parse().unwrap())?;
+    /// n.set_retransmission_profile(RetransmissionProfile::high_latency());
+    /// assert_eq!(n.retransmission_profile().max_attempts(), 6);
     /// # Ok(())
     /// # }
     /// ```
-    pub fn get_natpmp_request_timeout(&self) -> Result<Duration> {
-        if !self.has_pending_request {
-            return Err(Error::NATPMP_ERR_NOPENDINGREQ);
-        }
-        let now = Instant::now();
-        if now > self.retry_time {
-            return Ok(Duration::from_millis(0));
-        }
-        let duration = self.retry_time - now;
-        Ok(duration)
+    pub fn set_retransmission_profile(&mut self, profile: RetransmissionProfile) {
+        self.profile = profile;
     }
 
-    /// Send public address request.
+    /// Retransmission profile, see [`set_retransmission_profile`](struct.Natpmp.html#method.set_retransmission_profile).
+    pub fn retransmission_profile(&self) -> &RetransmissionProfile {
+        &self.profile
+    }
+
+    /// Randomize every retransmission timeout by up to `±jitter` (a fraction of the timeout).
+    ///
+    /// Spreads out the retransmissions of many clients started at the same time, e.g. after
+    /// a gateway reboot. Disabled (`0.0`) by default so timing stays deterministic.
     ///
     /// # Errors
-    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    /// * [`Error::NATPMP_ERR_INVALIDARGS`](enum.Error.html#variant.NATPMP_ERR_INVALIDARGS) if
+    ///   `jitter` is not within `0.0..=1.0`
     ///
     /// # Examples
     /// ```
     /// use natpmp::*;
     ///
     /// # fn main() -> Result<()> {
-    /// let mut n = Natpmp::new()?;
-    /// n.send_public_address_request()?;
-    /// // do something then read response
+    /// let mut n = Natpmp::new_with("192.168.0.1".parse().unwrap())?;
+    /// n.set_jitter(0.1)?;
+    /// assert_eq!(n.jitter(), 0.1);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_jitter(&mut self, jitter: f32) -> Result<()> {
+        self.jitter.set(jitter)
+    }
+
+    /// Retransmission jitter, see [`set_jitter`](struct.Natpmp.html#method.set_jitter).
+    pub fn jitter(&self) -> f32 {
+        self.jitter.fraction()
+    }
+
+    /// Seed the random source of the jitter, so retransmission timeouts are reproducible.
+    ///
+    /// The source is seeded randomly by default. The seed is part of
+    /// [`config`](struct.Natpmp.html#method.config), so a copy of the client retransmits on
+    /// the same timeouts.
+    pub fn set_jitter_seed(&mut self, seed: u64) {
+        self.jitter.set_seed(seed);
+    }
+
+    /// Discard every datagram waiting on the socket, returns how many were discarded.
+    ///
+    /// A late reply to a request which timed out may still be queued and would otherwise be
+    /// read as the response to the next request. Does not block.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_RECVFROM`](enum.Error.html#variant.NATPMP_ERR_RECVFROM)
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let mut n = Natpmp::new_with("192.168.0.1".parse().unwrap())?;
+    /// assert_eq!(n.drain()?, 0);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn drain(&mut self) -> Result<usize> {
+        let mut buf = [0u8; NATPMP_RECV_BUFFER_SIZE];
+        let mut drained = 0;
+        loop {
+            match self.s.recv(&mut buf) {
+                Ok(_) => drained += 1,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => match e.raw_os_error() {
+                    Some(code) if code == unsafe { RS_EWOULDBLOCK } => return Ok(drained),
+                    // a pending ICMP error from an earlier request
+                    Some(code) if code == unsafe { RS_ECONNREFUSED } => {}
+                    _ => return Err(Error::NATPMP_ERR_RECVFROM),
+                },
+            }
+        }
+    }
+
+    /// Whether to [`drain`](struct.Natpmp.html#method.drain) the socket before sending each
+    /// request, so that every request starts with an empty buffer. Disabled by default.
+    ///
+    /// Applies to the high-level helpers like [`map_port`](struct.Natpmp.html#method.map_port)
+    /// as well as the `send_*` methods.
+    pub fn set_drain_before_request(&mut self, enabled: bool) {
+        self.drain_before_request = enabled;
+    }
+
+    /// Whether the socket is drained before each request, see
+    /// [`set_drain_before_request`](struct.Natpmp.html#method.set_drain_before_request).
+    pub fn drain_before_request(&self) -> bool {
+        self.drain_before_request
+    }
+
+    /// Get timeout duration of the currently pending NAT-PMP request.
+    ///
+    /// # Errors:
+    /// * [`Error::NATPMP_ERR_NOPENDINGREQ`](enum.Error.html#variant.NATPMP_ERR_NOPENDINGREQ)
+    ///
+    /// # Examples
+    /// ```
+    /// use std::time::Duration;
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let mut n = Natpmp::new()?;
+    /// n.send_public_address_request()?;
+    /// // do something
+    /// let duration = n.get_natpmp_request_timeout()?;
+    /// if duration <= Duration::from_millis(10) {
+    ///     // read response ...
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn get_natpmp_request_timeout(&self) -> Result<Duration> {
+        if !self.has_pending_request {
+            return Err(Error::NATPMP_ERR_NOPENDINGREQ);
+        }
+        let now = Instant::now();
+        if now > self.retry_time {
+            return Ok(Duration::from_millis(0));
+        }
+        let duration = self.retry_time - now;
+        Ok(duration)
+    }
+
+    /// Send public address request.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let mut n = Natpmp::new()?;
+    /// n.send_public_address_request()?;
+    /// // do something then read response
     /// # Ok(())
     /// # }
     /// ```
     pub fn send_public_address_request(&mut self) -> Result<()> {
-        self.pending_request[0] = 0;
-        self.pending_request[1] = 0;
-        self.pending_request_len = 2;
+        let request = Natpmp::build_public_address_request();
+        self.pending_request[..2].copy_from_slice(&request);
+        self.pending_request_len = request.len();
         self.send_natpmp_request()
     }
 
     /// Send new port mapping request.
     ///
     /// # Errors
+    /// * [`Error::NATPMP_ERR_INVALIDPRIVATEPORT`](enum.Error.html#variant.NATPMP_ERR_INVALIDPRIVATEPORT)
+    ///   if `private_port` is 0, see [`destroy_all_mappings`](struct.Natpmp.html#method.destroy_all_mappings)
     /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
     ///
     /// # Examples
@@ -350,101 +1115,242 @@ impl Natpmp {
         public_port: u16,
         lifetime: u32,
     ) -> Result<()> {
-        self.pending_request[0] = 0;
-        self.pending_request[1] = match protocol {
-            Protocol::UDP => 1,
-            _ => 2,
-        };
-        self.pending_request[2] = 0; // reserved
-        self.pending_request[3] = 0; // reserved
-                                     // private port
-        self.pending_request[4] = (private_port >> 8 & 0xff) as u8;
-        self.pending_request[5] = (private_port & 0xff) as u8;
-        // public port
-        self.pending_request[6] = (public_port >> 8 & 0xff) as u8;
-        self.pending_request[7] = (public_port & 0xff) as u8;
-        // lifetime
-        self.pending_request[8] = ((lifetime >> 24) & 0xff) as u8;
-        self.pending_request[9] = ((lifetime >> 16) & 0xff) as u8;
-        self.pending_request[10] = ((lifetime >> 8) & 0xff) as u8;
-        self.pending_request[11] = (lifetime & 0xff) as u8;
+        self.pending_request =
+            Natpmp::build_mapping_request(protocol, private_port, public_port, lifetime)?;
         self.pending_request_len = 12;
         self.send_natpmp_request()
     }
 
-    fn read_response(&self) -> Result<Response> {
-        let mut buf = [0u8; 16];
-        match self.s.recv_from(&mut buf) {
-            Err(e) => match e.raw_os_error() {
-                Some(code) => {
-                    if code == unsafe { RS_EWOULDBLOCK } {
-                        return Err(Error::NATPMP_TRYAGAIN);
+    /// Send a request, see [`send_public_address_request`](struct.Natpmp.html#method.send_public_address_request)
+    /// and [`send_port_mapping_request`](struct.Natpmp.html#method.send_port_mapping_request).
+    ///
+    /// # Errors
+    /// See [`send_port_mapping_request`](struct.Natpmp.html#method.send_port_mapping_request).
+    pub fn send_request(&mut self, request: RequestKind) -> Result<()> {
+        match request {
+            RequestKind::PublicAddress => self.send_public_address_request(),
+            RequestKind::Mapping {
+                protocol,
+                private_port,
+                public_port,
+                lifetime,
+            } => self.send_port_mapping_request(protocol, private_port, public_port, lifetime),
+        }
+    }
+
+    /// Encode a public address request without sending it.
+    ///
+    /// These are the exact bytes
+    /// [`send_public_address_request`](struct.Natpmp.html#method.send_public_address_request)
+    /// transmits.
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// assert_eq!(Natpmp::build_public_address_request(), [0, 0]);
+    /// ```
+    pub fn build_public_address_request() -> [u8; 2] {
+        [0, 0]
+    }
+
+    /// Encode a port mapping request without sending it.
+    ///
+    /// These are the exact bytes
+    /// [`send_port_mapping_request`](struct.Natpmp.html#method.send_port_mapping_request)
+    /// transmits.
+    ///
+    /// A private port of 0 destroys every mapping of the protocol, which is only done
+    /// through [`build_destroy_all_request`](struct.Natpmp.html#method.build_destroy_all_request).
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_INVALIDPRIVATEPORT`](enum.Error.html#variant.NATPMP_ERR_INVALIDPRIVATEPORT)
+    ///   if `private_port` is 0
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let request = Natpmp::build_mapping_request(Protocol::TCP, 4020, 4021, 30)?;
+    /// assert_eq!(request, [0, 2, 0, 0, 0x0f, 0xb4, 0x0f, 0xb5, 0, 0, 0, 30]);
+    /// assert_eq!(
+    ///     Natpmp::build_mapping_request(Protocol::TCP, 0, 4021, 30),
+    ///     Err(Error::NATPMP_ERR_INVALIDPRIVATEPORT)
+    /// );
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn build_mapping_request(
+        protocol: Protocol,
+        private_port: u16,
+        public_port: u16,
+        lifetime: u32,
+    ) -> Result<[u8; 12]> {
+        if private_port == 0 {
+            return Err(Error::NATPMP_ERR_INVALIDPRIVATEPORT);
+        }
+        Ok(encode_mapping_request(
+            protocol,
+            private_port,
+            public_port,
+            lifetime,
+        ))
+    }
+
+    /// Encode a request destroying every mapping of `protocol` created by this host.
+    ///
+    /// # Examples
+    /// ```
+    /// use natpmp::*;
+    ///
+    /// let request = Natpmp::build_destroy_all_request(Protocol::UDP);
+    /// assert_eq!(request, [0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    /// ```
+    pub fn build_destroy_all_request(protocol: Protocol) -> [u8; 12] {
+        encode_mapping_request(protocol, 0, 0, 0)
+    }
+
+    /// Send a public address request to another gateway than the client's.
+    ///
+    /// The request is not retransmitted, read the response with
+    /// [`read_response_from`](struct.Natpmp.html#method.read_response_from).
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_INVALIDARGS`](enum.Error.html#variant.NATPMP_ERR_INVALIDARGS) if the
+    ///   client is connected, see [`SocketMode`](enum.SocketMode.html)
+    /// * [`Error::NATPMP_ERR_SENDERR`](enum.Error.html#variant.NATPMP_ERR_SENDERR)
+    pub fn send_public_address_request_to(&self, gateway: Ipv4Addr) -> Result<()> {
+        if self.mode == SocketMode::Connected {
+            return Err(Error::NATPMP_ERR_INVALIDARGS);
+        }
+        self.send_to(gateway, &Natpmp::build_public_address_request())
+    }
+
+    /// Send a port mapping request to another gateway than the client's.
+    ///
+    /// See [`send_public_address_request_to`](struct.Natpmp.html#method.send_public_address_request_to).
+    ///
+    /// # Errors
+    /// See [`send_public_address_request_to`](struct.Natpmp.html#method.send_public_address_request_to).
+    pub fn send_port_mapping_request_to(
+        &self,
+        gateway: Ipv4Addr,
+        protocol: Protocol,
+        private_port: u16,
+        public_port: u16,
+        lifetime: u32,
+    ) -> Result<()> {
+        if self.mode == SocketMode::Connected {
+            return Err(Error::NATPMP_ERR_INVALIDARGS);
+        }
+        let request = Natpmp::build_mapping_request(protocol, private_port, public_port, lifetime)?;
+        self.send_to(gateway, &request)
+    }
+
+    /// Wait up to `timeout` for a response from `gateway`, skipping other datagrams.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_TRYAGAIN`](enum.Error.html#variant.NATPMP_TRYAGAIN) if the timeout elapsed
+    /// * [`Error::NATPMP_ERR_INVALIDARGS`](enum.Error.html#variant.NATPMP_ERR_INVALIDARGS) if the
+    ///   client is connected
+    /// * [`Error::NATPMP_ERR_FCNTLERROR`](enum.Error.html#variant.NATPMP_ERR_FCNTLERROR)
+    /// * [`Error::NATPMP_ERR_RECVFROM`](enum.Error.html#variant.NATPMP_ERR_RECVFROM)
+    /// * Errors returned by the gateway, see [`decode_response`](fn.decode_response.html)
+    pub fn read_response_from(&self, gateway: Ipv4Addr, timeout: Duration) -> Result<Response> {
+        if self.mode == SocketMode::Connected {
+            return Err(Error::NATPMP_ERR_INVALIDARGS);
+        }
+        let source = SocketAddr::from((gateway, NATPMP_PORT));
+        let deadline = Instant::now() + timeout;
+        let mut buf = [0u8; NATPMP_RECV_BUFFER_SIZE];
+        if self.s.set_nonblocking(false).is_err() {
+            return Err(Error::NATPMP_ERR_FCNTLERROR);
+        }
+        let result = loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_millis(0) {
+                break Err(Error::NATPMP_TRYAGAIN);
+            }
+            if self.s.set_read_timeout(Some(remaining)).is_err() {
+                break Err(Error::NATPMP_ERR_FCNTLERROR);
+            }
+            match self.s.recv_from(&mut buf) {
+                Ok((n, addr)) => {
+                    if addr != source {
+                        continue;
                     }
-                    if code == unsafe { RS_ECONNREFUSED } {
-                        return Err(Error::NATPMP_ERR_NOGATEWAYSUPPORT);
+                    if check_response_header(&buf[..n]).is_err() {
+                        continue;
                     }
+                    break decode_response(&buf[..n]);
                 }
-                _ => {
-                    return Err(Error::NATPMP_ERR_RECVFROM);
-                }
-            },
-            Ok((_, sockaddr)) => {
-                // check gateway address
-                if let SocketAddr::V4(s) = sockaddr {
-                    if s.ip() != &self.gateway {
-                        return Err(Error::NATPMP_ERR_WRONGPACKETSOURCE);
+                Err(e) => match e.kind() {
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+                        break Err(Error::NATPMP_TRYAGAIN)
+                    }
+                    // a refused request to another gateway
+                    io::ErrorKind::ConnectionRefused | io::ErrorKind::Interrupted => continue,
+                    _ => break Err(Error::NATPMP_ERR_RECVFROM),
+                },
+            }
+        };
+        if self.s.set_nonblocking(true).is_err() {
+            return Err(Error::NATPMP_ERR_FCNTLERROR);
+        }
+        result
+    }
+
+    fn read_response(&self) -> Result<Response> {
+        self.read_response_with(|buf| self.s.recv_from(buf))
+    }
+
+    /// [`read_response`](struct.Natpmp.html#method.read_response) receiving with `recv`, so
+    /// tests can script the socket.
+    fn read_response_with<F>(&self, mut recv: F) -> Result<Response>
+    where
+        F: FnMut(&mut [u8]) -> io::Result<(usize, SocketAddr)>,
+    {
+        let mut buf = [0u8; NATPMP_RECV_BUFFER_SIZE];
+        loop {
+            match recv(&mut buf) {
+                // interrupted by a signal, not a failed attempt
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => match e.raw_os_error() {
+                    Some(code) => {
+                        if code == unsafe { RS_EWOULDBLOCK } {
+                            return Err(Error::NATPMP_TRYAGAIN);
+                        }
+                        if code == unsafe { RS_ECONNREFUSED } {
+                            return Err(Error::NATPMP_ERR_NOGATEWAYSUPPORT);
+                        }
+                        return Err(Error::NATPMP_ERR_RECVFROM);
                     }
-                }
-                // version
-                if buf[0] != 0 {
-                    return Err(Error::NATPMP_ERR_UNSUPPORTEDVERSION);
-                }
-                // opcode
-                if buf[1] < 128 || buf[1] > 130 {
-                    return Err(Error::NATPMP_ERR_UNSUPPORTEDOPCODE);
-                }
-                // result code
-                let resultcode = u16::from_be(convert_to(&buf[2..4]));
-                if resultcode != 0 {
-                    return Err(match resultcode {
-                        1 => Error::NATPMP_ERR_UNSUPPORTEDVERSION,
-                        2 => Error::NATPMP_ERR_NOTAUTHORIZED,
-                        3 => Error::NATPMP_ERR_NETWORKFAILURE,
-                        4 => Error::NATPMP_ERR_OUTOFRESOURCES,
-                        5 => Error::NATPMP_ERR_UNSUPPORTEDOPCODE,
-                        _ => Error::NATPMP_ERR_UNDEFINEDERROR,
-                    });
-                }
-                // epoch
-                let epoch = u32::from_be(convert_to(&buf[4..8]));
-                // result
-                let rsp_type = buf[1] & 0x7f;
-                return Ok(match rsp_type {
-                    0 => Response::Gateway(GatewayResponse {
-                        epoch,
-                        public_address: Ipv4Addr::from(u32::from_be(convert_to(&buf[8..12]))),
-                    }),
                     _ => {
-                        let private_port = u16::from_be(convert_to(&buf[8..10]));
-                        let public_port = u16::from_be(convert_to(&buf[10..12]));
-                        let lifetime = u32::from_be(convert_to(&buf[12..16]));
-                        let lifetime = Duration::from_secs(u64::from(lifetime));
-                        let m = MappingResponse {
-                            epoch,
-                            private_port,
-                            public_port,
-                            lifetime,
-                        };
-                        if rsp_type == 1 {
-                            Response::UDP(m)
-                        } else {
-                            Response::TCP(m)
+                        return Err(Error::NATPMP_ERR_RECVFROM);
+                    }
+                },
+                Ok((n, sockaddr)) => {
+                    // check gateway address
+                    if self.mode == SocketMode::Unconnected {
+                        if sockaddr != SocketAddr::from((self.gateway, NATPMP_PORT)) {
+                            continue;
                         }
+                    } else if let SocketAddr::V4(s) = sockaddr {
+                        if s.ip() != &self.gateway {
+                            return Err(Error::NATPMP_ERR_WRONGPACKETSOURCE);
+                        }
+                    }
+                    log::debug!("received from {}: {}", sockaddr, to_hex(&buf[..n]));
+                    // skip empty or short datagrams and those which are not responses,
+                    // a valid one may follow
+                    if check_response_header(&buf[..n]).is_err() {
+                        continue;
                     }
-                });
+                    return decode_response(&buf[..n]);
+                }
             }
         }
-        Err(Error::NATPMP_ERR_RECVFROM)
     }
 
     /// Read NAT-PMP response if possible
@@ -459,6 +1365,7 @@ impl Natpmp {
     /// * [`Error::NATPMP_ERR_UNSUPPORTEDOPCODE`](enum.Error.html#variant.NATPMP_ERR_UNSUPPORTEDOPCODE)
     /// * [`Error::NATPMP_ERR_UNSUPPORTEDVERSION`](enum.Error.html#variant.NATPMP_ERR_UNSUPPORTEDVERSION)
     /// * [`Error::NATPMP_ERR_NOTAUTHORIZED`](enum.Error.html#variant.NATPMP_ERR_NOTAUTHORIZED)
+    /// * [`Error::NATPMP_ERR_MAPPINGDISABLED`](enum.Error.html#variant.NATPMP_ERR_MAPPINGDISABLED)
     /// * [`Error::NATPMP_ERR_NETWORKFAILURE`](enum.Error.html#variant.NATPMP_ERR_NETWORKFAILURE)
     /// * [`Error::NATPMP_ERR_OUTOFRESOURCES`](enum.Error.html#variant.NATPMP_ERR_OUTOFRESOURCES)
     /// * [`Error::NATPMP_ERR_UNSUPPORTEDOPCODE`](enum.Error.html#variant.NATPMP_ERR_OUTOFRESOURCES)
@@ -490,13 +1397,13 @@ impl Natpmp {
                     let now = Instant::now();
                     // time to retry or not
                     if now >= self.retry_time {
-                        if self.try_number >= NATPMP_MAX_ATTEMPS {
+                        if self.try_number >= self.profile.max_attempts() {
                             return Err(Error::NATPMP_ERR_NOGATEWAYSUPPORT);
                         }
                         // double dealy
-                        let delay = (NATPMP_MIN_WAIT * (1 << self.try_number)) as u64; // ms
-                        self.retry_time = self.retry_time.add(Duration::from_millis(delay)); // next time
                         self.try_number += 1;
+                        let delay = self.retransmission_delay(self.try_number);
+                        self.retry_time = self.retry_time.add(delay); // next time
                         self.send_pending_request()?;
                     }
                 }
@@ -505,10 +1412,381 @@ impl Natpmp {
         }
         result
     }
+
+    /// Block until the socket is readable or `timeout` elapsed.
+    fn wait_readable(&self, timeout: Duration) -> Result<()> {
+        if timeout == Duration::from_millis(0) {
+            return Ok(());
+        }
+        if self.s.set_nonblocking(false).is_err() || self.s.set_read_timeout(Some(timeout)).is_err()
+        {
+            return Err(Error::NATPMP_ERR_FCNTLERROR);
+        }
+        // whatever the outcome, the following read reports it
+        let _ = self.s.peek_from(&mut [0u8; 1]);
+        if self.s.set_nonblocking(true).is_err() {
+            return Err(Error::NATPMP_ERR_FCNTLERROR);
+        }
+        Ok(())
+    }
+
+    /// Block until the pending request is answered, retrying as needed.
+    fn wait_response(&mut self) -> Result<Response> {
+        loop {
+            match self.read_response_or_retry() {
+                Err(Error::NATPMP_TRYAGAIN) => {
+                    let timeout = self.get_natpmp_request_timeout()?;
+                    self.wait_readable(timeout)?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn wait_mapping_response(&mut self, protocol: Protocol) -> Result<MappingResponse> {
+        match (protocol, self.wait_response()?) {
+            (Protocol::UDP, Response::UDP(m)) | (Protocol::TCP, Response::TCP(m)) => Ok(m),
+            _ => Err(Error::NATPMP_ERR_UNSUPPORTEDOPCODE),
+        }
+    }
+
+    /// Request the public address of the gateway and block until it answers.
+    ///
+    /// # Errors
+    /// See [`read_response_or_retry`](struct.Natpmp.html#method.read_response_or_retry).
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let mut n = Natpmp::new()?;
+    /// println!("public address {}", n.external_address()?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn external_address(&mut self) -> Result<Ipv4Addr> {
+        self.send_public_address_request()?;
+        match self.wait_response()? {
+            Response::Gateway(gr) => Ok(*gr.public_address()),
+            _ => Err(Error::NATPMP_ERR_UNSUPPORTEDOPCODE),
+        }
+    }
+
+    /// Request a port mapping and block until the gateway answers.
+    ///
+    /// The gateway may assign a public port other than `public_port`, see
+    /// [`map_port_strict`](struct.Natpmp.html#method.map_port_strict).
+    ///
+    /// # Errors
+    /// See [`read_response_or_retry`](struct.Natpmp.html#method.read_response_or_retry).
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let mut n = Natpmp::new()?;
+    /// let mapping = n.map_port(Protocol::TCP, 8080, 8080, 3600)?;
+    /// println!("mapped to public port {}", mapping.public_port());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn map_port(
+        &mut self,
+        protocol: Protocol,
+        private_port: u16,
+        public_port: u16,
+        lifetime: u32,
+    ) -> Result<MappingResponse> {
+        self.send_port_mapping_request(protocol, private_port, public_port, lifetime)?;
+        let m = self.wait_mapping_response(protocol)?;
+        let granted = m.lifetime().as_secs();
+        let requested = u64::from(lifetime);
+        if granted < requested / 2 || granted > requested.saturating_mul(2) {
+            log::warn!(
+                "gateway {} granted a lifetime of {}s for port {}, {}s requested",
+                self.gateway,
+                granted,
+                private_port,
+                requested
+            );
+        }
+        Ok(m)
+    }
+
+    /// Map the port `listener` is bound to, requesting the same public port.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR) if
+    ///   the address of `listener` is unknown
+    /// * See [`map_port`](struct.Natpmp.html#method.map_port).
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use std::net::TcpListener;
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let listener = TcpListener::bind("0.0.0.0:0").unwrap();
+    /// let mut n = Natpmp::new()?;
+    /// let mapping = n.map_listener(&listener, 3600)?;
+    /// println!("listening on public port {}", mapping.public_port());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn map_listener(
+        &mut self,
+        listener: &TcpListener,
+        lifetime: u32,
+    ) -> Result<MappingResponse> {
+        let port = listener
+            .local_addr()
+            .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?
+            .port();
+        self.map_port(Protocol::TCP, port, port, lifetime)
+    }
+
+    /// Map the port `socket` is bound to, requesting the same public port.
+    ///
+    /// # Errors
+    /// See [`map_listener`](struct.Natpmp.html#method.map_listener).
+    pub fn map_udp_socket(&mut self, socket: &UdpSocket, lifetime: u32) -> Result<MappingResponse> {
+        let port = socket
+            .local_addr()
+            .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?
+            .port();
+        self.map_port(Protocol::UDP, port, port, lifetime)
+    }
+
+    /// Request a port mapping like [`map_port`](struct.Natpmp.html#method.map_port), with a
+    /// typed lifetime.
+    ///
+    /// # Errors
+    /// * See [`lifetime_secs`](fn.lifetime_secs.html).
+    /// * See [`map_port`](struct.Natpmp.html#method.map_port).
+    pub fn map_port_for(
+        &mut self,
+        protocol: Protocol,
+        private_port: u16,
+        public_port: u16,
+        lifetime: Duration,
+    ) -> Result<MappingResponse> {
+        let lifetime = lifetime_secs(lifetime)?;
+        self.map_port(protocol, private_port, public_port, lifetime)
+    }
+
+    /// Request a port mapping like [`map_port`](struct.Natpmp.html#method.map_port), also
+    /// returning a [`MappingHandle`](struct.MappingHandle.html) to manage it later.
+    ///
+    /// # Errors
+    /// See [`map_port`](struct.Natpmp.html#method.map_port).
+    pub fn map_port_handle(
+        &mut self,
+        protocol: Protocol,
+        private_port: u16,
+        public_port: u16,
+        lifetime: u32,
+    ) -> Result<(MappingHandle, MappingResponse)> {
+        let m = self.map_port(protocol, private_port, public_port, lifetime)?;
+        Ok((MappingHandle::new(protocol, &m), m))
+    }
+
+    /// Check the gateway still holds the mapping of `handle`.
+    ///
+    /// The mapping is requested again with its granted public port and lifetime, which also
+    /// refreshes it. Returns `false` if the gateway assigned another public port, the mapping
+    /// having disappeared meanwhile, or if the epoch shows the gateway rebooted since the
+    /// mapping was established, see [`EpochTracker`](struct.EpochTracker.html). A mapping on
+    /// another public port is left in place. This only checks the gateway's state, not that
+    /// the port is reachable from outside.
+    ///
+    /// # Errors
+    /// * See [`lifetime_secs`](fn.lifetime_secs.html) for a handle built from parts.
+    /// * See [`map_port`](struct.Natpmp.html#method.map_port).
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let mut n = Natpmp::new()?;
+    /// let (handle, _) = n.map_port_handle(Protocol::TCP, 8080, 8080, 3600)?;
+    /// if !n.verify_mapping(&handle)? {
+    ///     n.map_port_handle(Protocol::TCP, 8080, 8080, 3600)?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn verify_mapping(&mut self, handle: &MappingHandle) -> Result<bool> {
+        let lifetime = lifetime_secs(*handle.lifetime())?;
+        let m = self.map_port(
+            handle.protocol(),
+            handle.private_port(),
+            handle.public_port(),
+            lifetime,
+        )?;
+        Ok(m.public_port() == handle.public_port() && !handle.rebooted_since(m.epoch()))
+    }
+
+    /// Request a port mapping, failing if the gateway assigns another public port.
+    ///
+    /// A mapping on another public port is destroyed before returning.
+    ///
+    /// Gateways refusing a public port taken by another host answer not authorized or out of
+    /// resources, depending on the vendor, like for any other refusal. To tell them apart, the
+    /// private port is then requested once more on any public port and, if the gateway maps
+    /// it, the mapping is destroyed and the public port reported unavailable. This is a
+    /// heuristic: the gateway may have refused for another reason that no longer applies, the
+    /// probe briefly creates a mapping, and a gateway refusing the probe too cannot be
+    /// classified.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_PUBLICPORTMISMATCH`](enum.Error.html#variant.NATPMP_ERR_PUBLICPORTMISMATCH)
+    /// * [`Error::NATPMP_ERR_PUBLICPORTUNAVAILABLE`](enum.Error.html#variant.NATPMP_ERR_PUBLICPORTUNAVAILABLE)
+    ///   if the gateway refused `public_port` but mapped another one
+    /// * See [`map_port`](struct.Natpmp.html#method.map_port).
+    pub fn map_port_strict(
+        &mut self,
+        protocol: Protocol,
+        private_port: u16,
+        public_port: u16,
+        lifetime: u32,
+    ) -> Result<MappingResponse> {
+        let m = match self.map_port(protocol, private_port, public_port, lifetime) {
+            Err(e @ Error::NATPMP_ERR_MAPPINGDISABLED)
+            | Err(e @ Error::NATPMP_ERR_OUTOFRESOURCES)
+                if public_port != 0 =>
+            {
+                return Err(self.classify_refusal(protocol, private_port, lifetime, e));
+            }
+            result => result?,
+        };
+        if m.public_port() != public_port {
+            let _ = self.destroy_mapping(protocol, private_port);
+            return Err(Error::NATPMP_ERR_PUBLICPORTMISMATCH);
+        }
+        Ok(m)
+    }
+
+    /// Whether `refusal` of a public port was because it is taken, probing with any public port.
+    fn classify_refusal(
+        &mut self,
+        protocol: Protocol,
+        private_port: u16,
+        lifetime: u32,
+        refusal: Error,
+    ) -> Error {
+        match self.map_port(protocol, private_port, 0, lifetime) {
+            Ok(_) => {
+                let _ = self.destroy_mapping(protocol, private_port);
+                Error::NATPMP_ERR_PUBLICPORTUNAVAILABLE
+            }
+            Err(_) => refusal,
+        }
+    }
+
+    /// Make sure a mapping from `public_port` to `private_port` exists.
+    ///
+    /// Mapping requests are idempotent and refresh an existing mapping. A gateway keeps the
+    /// public port of an existing mapping of the private port, so if it assigns another public
+    /// port the mapping is destroyed and requested once more. The second request waits for
+    /// the first timeout of the retransmission profile, with jitter applied, so an overloaded
+    /// gateway is not asked again at once.
+    ///
+    /// # Errors
+    /// See [`map_port_strict`](struct.Natpmp.html#method.map_port_strict).
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let mut n = Natpmp::new()?;
+    /// let mapping = n.ensure_mapping(Protocol::TCP, 8080, 8080, 3600)?;
+    /// assert_eq!(mapping.public_port(), 8080);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn ensure_mapping(
+        &mut self,
+        protocol: Protocol,
+        private_port: u16,
+        public_port: u16,
+        lifetime: u32,
+    ) -> Result<MappingResponse> {
+        match self.map_port_strict(protocol, private_port, public_port, lifetime) {
+            Err(Error::NATPMP_ERR_PUBLICPORTMISMATCH) => {
+                thread::sleep(self.retransmission_delay(1));
+                self.map_port_strict(protocol, private_port, public_port, lifetime)
+            }
+            result => result,
+        }
+    }
+
+    /// Destroy the mapping of `private_port`, requesting it with a lifetime of 0.
+    ///
+    /// The suggested public port is 0: rfc6886 section 3.4 requires it on deletion and
+    /// gateways ignore it, so a specific public port cannot be destroyed. A host has at most
+    /// one mapping per private port and protocol, so this never destroys more than that one.
+    /// See [`destroy_all_mappings`](struct.Natpmp.html#method.destroy_all_mappings) for the
+    /// wildcard deletion.
+    ///
+    /// # Errors
+    /// See [`map_port`](struct.Natpmp.html#method.map_port).
+    pub fn destroy_mapping(&mut self, protocol: Protocol, private_port: u16) -> Result<()> {
+        self.map_port(protocol, private_port, 0, 0).map(|_| ())
+    }
+
+    /// Destroy both the UDP and the TCP mapping of `private_port`.
+    ///
+    /// The TCP mapping is destroyed even if destroying the UDP mapping fails.
+    ///
+    /// # Errors
+    /// The UDP error if destroying the UDP mapping failed, the TCP error is then only logged.
+    /// Otherwise the TCP error, see
+    /// [`destroy_mapping`](struct.Natpmp.html#method.destroy_mapping).
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let mut n = Natpmp::new()?;
+    /// n.destroy_mapping_both(8080)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn destroy_mapping_both(&mut self, private_port: u16) -> Result<()> {
+        let udp = self.destroy_mapping(Protocol::UDP, private_port);
+        let tcp = self.destroy_mapping(Protocol::TCP, private_port);
+        if let (Err(_), Err(e)) = (&udp, &tcp) {
+            log::warn!(
+                "destroying tcp mapping of port {} failed: {}",
+                private_port,
+                e
+            );
+        }
+        udp.and(tcp)
+    }
+
+    /// Destroy every mapping of `protocol` created by this host.
+    ///
+    /// The only way to send a mapping request with a private port of 0, see rfc6886
+    /// section 3.4.
+    ///
+    /// # Errors
+    /// See [`map_port`](struct.Natpmp.html#method.map_port).
+    pub fn destroy_all_mappings(&mut self, protocol: Protocol) -> Result<()> {
+        self.pending_request = Natpmp::build_destroy_all_request(protocol);
+        self.pending_request_len = 12;
+        self.send_natpmp_request()?;
+        self.wait_mapping_response(protocol).map(|_| ())
+    }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use std::thread;
     use std::time::Duration;
 
@@ -516,14 +1794,14 @@ mod tests {
 
     #[test]
     fn test_ffi() {
-        assert_eq!(true, get_default_gateway().is_ok());
+        assert!(get_default_gateway().is_ok());
         assert_ne!(0, unsafe { RS_EWOULDBLOCK });
         assert_ne!(0, unsafe { RS_ECONNREFUSED });
     }
 
     #[test]
     fn test_natpmp() -> Result<()> {
-        assert_eq!(Natpmp::new().is_ok(), true);
+        assert!(Natpmp::new().is_ok());
         let addr = "192.168.0.1".parse().unwrap();
         let n = Natpmp::new_with(addr)?;
         assert_eq!(*n.gateway(), addr);
@@ -584,16 +1862,783 @@ mod tests {
 
         n.send_port_mapping_request(Protocol::UDP, 14021, 14020, 10)?;
         thread::sleep(Duration::from_millis(250));
-        match n.read_response_or_retry() {
-            Ok(r) => {
-                if let Response::UDP(ur) = r {
-                    assert_ne!(ur.public_port(), 14020);
-                } else {
-                    panic!("Not a udp mapping response!");
-                }
+        if let Ok(r) = n.read_response_or_retry() {
+            if let Response::UDP(ur) = r {
+                assert_ne!(ur.public_port(), 14020);
+            } else {
+                panic!("Not a udp mapping response!");
+            }
+        }
+        Ok(())
+    }
+
+    /// Serve `requests` requests on `ip` with `respond`, returns the requests received.
+    pub(crate) fn fake_gateway<F>(
+        ip: Ipv4Addr,
+        requests: usize,
+        mut respond: F,
+    ) -> thread::JoinHandle<Vec<Vec<u8>>>
+    where
+        F: FnMut(&[u8]) -> Vec<u8> + Send + 'static,
+    {
+        let s = UdpSocket::bind((ip, NATPMP_PORT)).unwrap();
+        thread::spawn(move || {
+            let mut received = Vec::new();
+            let mut buf = [0u8; 12];
+            for _ in 0..requests {
+                let (n, addr) = s.recv_from(&mut buf).unwrap();
+                s.send_to(&respond(&buf[..n]), addr).unwrap();
+                received.push(buf[..n].to_vec());
+            }
+            received
+        })
+    }
+
+    /// Serve one mapping request on `ip` after losing the first `lost` requests, returns how
+    /// many requests were received, including retransmissions arriving shortly after the answer.
+    pub(crate) fn lossy_gateway(ip: Ipv4Addr, lost: usize) -> thread::JoinHandle<usize> {
+        let s = UdpSocket::bind((ip, NATPMP_PORT)).unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 12];
+            for _ in 0..lost {
+                s.recv_from(&mut buf).unwrap();
+            }
+            let (n, addr) = s.recv_from(&mut buf).unwrap();
+            s.send_to(&mapping_response(&buf[..n], 9000, 60), addr)
+                .unwrap();
+            let mut received = lost + 1;
+            s.set_read_timeout(Some(Duration::from_millis(500)))
+                .unwrap();
+            while s.recv_from(&mut buf).is_ok() {
+                received += 1;
+            }
+            received
+        })
+    }
+
+    /// Answer a mapping request with `public_port` and `lifetime`.
+    pub(crate) fn mapping_response(request: &[u8], public_port: u16, lifetime: u32) -> Vec<u8> {
+        let mut buf = vec![0u8; 16];
+        buf[1] = request[1] + 128;
+        buf[8..10].copy_from_slice(&request[4..6]);
+        buf[10..12].copy_from_slice(&public_port.to_be_bytes());
+        buf[12..16].copy_from_slice(&lifetime.to_be_bytes());
+        buf
+    }
+
+    #[test]
+    fn test_verify_mapping() -> Result<()> {
+        let gateway = Ipv4Addr::new(127, 0, 0, 26);
+        let mut granted = vec![6200, 6200, 6200, 6201].into_iter();
+        let g = fake_gateway(gateway, 4, move |request| {
+            mapping_response(request, granted.next().unwrap(), 120)
+        });
+        let mut n = Natpmp::new_with(gateway)?;
+        let (handle, _) = n.map_port_handle(Protocol::TCP, 6200, 6200, 120)?;
+        assert!(n.verify_mapping(&handle)?);
+        // the gateway counted from 0 again an hour after the mapping was established
+        let established = SystemTime::now() - Duration::from_secs(3600);
+        let old = MappingHandle::from_parts(
+            Protocol::TCP,
+            6200,
+            6200,
+            Duration::from_secs(120),
+            0,
+            established,
+        );
+        assert!(!n.verify_mapping(&old)?);
+        // the mapping disappeared and its port was given to another host
+        assert!(!n.verify_mapping(&handle)?);
+        let requests = g.join().unwrap();
+        assert_eq!(
+            requests[1],
+            Natpmp::build_mapping_request(Protocol::TCP, 6200, 6200, 120).unwrap()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_refused_after_send() -> Result<()> {
+        // nothing listens, the send succeeds and the port unreachable fails the receive
+        let mut n = Natpmp::new_with(Ipv4Addr::new(127, 0, 0, 27))?;
+        n.set_retransmission_profile(RetransmissionProfile::fast_fail());
+        let start = Instant::now();
+        assert_eq!(
+            n.external_address(),
+            Err(Error::NATPMP_ERR_NOGATEWAYSUPPORT)
+        );
+        // without waiting for a retransmission
+        assert!(start.elapsed() < Duration::from_millis(NATPMP_MIN_WAIT));
+        Ok(())
+    }
+
+    #[test]
+    fn test_interrupted() -> Result<()> {
+        let gateway = Ipv4Addr::new(192, 168, 0, 1);
+        let n = Natpmp::new_with(gateway)?;
+        let mut script: Vec<io::Result<Vec<u8>>> = vec![
+            Err(io::ErrorKind::Interrupted.into()),
+            Err(io::ErrorKind::Interrupted.into()),
+            Ok(vec![0, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 7]),
+        ];
+        script.reverse();
+        let r = n.read_response_with(|buf| {
+            let datagram = script.pop().expect("read past the interruptions")?;
+            buf[..datagram.len()].copy_from_slice(&datagram);
+            Ok((datagram.len(), SocketAddr::from((gateway, NATPMP_PORT))))
+        });
+        // neither reported as a receive failure nor a try again
+        assert!(matches!(r, Ok(Response::Gateway(_))));
+        assert!(script.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_gateway_detection_supported() {
+        let unsupported = get_default_gateway() == Err(Error::NATPMP_ERR_UNSUPPORTEDPLATFORM);
+        assert_eq!(unsupported, !GATEWAY_DETECTION_SUPPORTED);
+    }
+
+    #[test]
+    fn test_decode_response_length() -> Result<()> {
+        let mut buf = [0u8; 20];
+        buf[1] = 129;
+        buf[8..10].copy_from_slice(&4020u16.to_be_bytes());
+        buf[10..12].copy_from_slice(&4021u16.to_be_bytes());
+        buf[12..16].copy_from_slice(&30u32.to_be_bytes());
+        buf[16..].copy_from_slice(&[1, 2, 3, 4]);
+        // trailing bytes are kept aside
+        let (response, extra) = decode_response_with_extra(&buf)?;
+        assert_eq!(extra, &[1, 2, 3, 4]);
+        assert_eq!(decode_response(&buf)?, response);
+        match response {
+            Response::UDP(ur) => {
+                assert_eq!(ur.private_port(), 4020);
+                assert_eq!(ur.public_port(), 4021);
+                assert_eq!(ur.lifetime(), &Duration::from_secs(30));
+            }
+            _ => panic!("Not a udp mapping response"),
+        }
+        // truncated datagrams are rejected
+        for short in [&buf[..15], &[], &[0]] {
+            let e = decode_response(short).unwrap_err();
+            assert_eq!(e, Error::NATPMP_ERR_MALFORMEDRESPONSE);
+            assert!(e.is_malformed_response());
+        }
+        // each shape has its own minimum size
+        let gateway = [0u8, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 7];
+        match decode_response_with_extra(&gateway)? {
+            (Response::Gateway(gr), extra) => {
+                assert_eq!(gr.public_address(), &Ipv4Addr::new(203, 0, 113, 7));
+                assert!(extra.is_empty());
+            }
+            _ => panic!("Not a gateway response"),
+        }
+        assert_eq!(
+            decode_response(&gateway[..11]),
+            Err(Error::NATPMP_ERR_MALFORMEDRESPONSE)
+        );
+        let mut mapping = gateway;
+        mapping[1] = 130;
+        assert_eq!(
+            decode_response(&mapping),
+            Err(Error::NATPMP_ERR_MALFORMEDRESPONSE)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_arbitrary() {
+        // same property as the fuzz target, on a fixed sample
+        let mut rng = fastrand::Rng::with_seed(6886);
+        for _ in 0..10_000 {
+            let mut buf = vec![0u8; rng.usize(0..24)];
+            rng.fill(&mut buf);
+            if !buf.is_empty() && rng.bool() {
+                buf[0] = 0;
+            }
+            if buf.len() > 1 && rng.bool() {
+                buf[1] = rng.u8(128..=130);
+            }
+            if let Ok((_, extra)) = decode_response_with_extra(&buf) {
+                assert!(buf.len() >= 12);
+                assert!(extra.len() < buf.len());
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_request() -> Result<()> {
+        assert_eq!(
+            decode_request(&Natpmp::build_public_address_request())?,
+            RequestKind::PublicAddress
+        );
+        let mapping = Natpmp::build_mapping_request(Protocol::UDP, 4020, 4021, 30)?;
+        let decoded = decode_request(&mapping)?;
+        assert_eq!(
+            decoded,
+            RequestKind::Mapping {
+                protocol: Protocol::UDP,
+                private_port: 4020,
+                public_port: 4021,
+                lifetime: 30,
+            }
+        );
+        for n in 0..mapping.len() {
+            assert_eq!(
+                decode_request(&mapping[..n]),
+                Err(Error::NATPMP_ERR_MALFORMEDREQUEST)
+            );
+        }
+        let destroy_all = Natpmp::build_destroy_all_request(Protocol::TCP);
+        assert!(decode_request(&destroy_all).is_ok());
+        let mut invalid = destroy_all;
+        invalid[11] = 60;
+        assert_eq!(
+            decode_request(&invalid),
+            Err(Error::NATPMP_ERR_INVALIDPRIVATEPORT)
+        );
+        assert_eq!(
+            decode_request(&[1, 0]),
+            Err(Error::NATPMP_ERR_UNSUPPORTEDVERSION)
+        );
+        assert_eq!(
+            decode_request(&[0, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 7]),
+            Err(Error::NATPMP_ERR_UNSUPPORTEDOPCODE)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_lifetime_secs() {
+        let max = Duration::from_secs(u64::from(u32::MAX));
+        assert_eq!(lifetime_secs(max), Ok(u32::MAX));
+        assert_eq!(
+            lifetime_secs(max + Duration::from_millis(999)),
+            Ok(u32::MAX)
+        );
+        assert_eq!(
+            lifetime_secs(max + Duration::from_secs(1)),
+            Err(Error::NATPMP_ERR_INVALIDLIFETIME)
+        );
+        assert_eq!(
+            lifetime_secs(Duration::MAX),
+            Err(Error::NATPMP_ERR_INVALIDLIFETIME)
+        );
+    }
+
+    #[test]
+    fn test_to_hex() {
+        assert_eq!(to_hex(&[]), "");
+        let response = [0u8, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 7];
+        assert_eq!(to_hex(&response), "00 80 00 00 00 00 00 01 cb 00 71 07");
+        assert_eq!(RequestKind::PublicAddress.to_hex(), "00 00");
+    }
+
+    #[test]
+    fn test_decode_adversarial() {
+        let gateway = [0u8, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 7];
+        let mapping = [0u8, 130, 0, 0, 0, 0, 0, 1, 0, 22, 0, 22, 0, 0, 0, 60];
+        // every truncation
+        for valid in [&gateway[..], &mapping[..]] {
+            for n in 0..valid.len() {
+                assert!(decode_response(&valid[..n]).is_err());
+            }
+            assert!(decode_response(valid).is_ok());
+        }
+        // every version and opcode
+        for version in 0..=255u8 {
+            for opcode in 0..=255u8 {
+                let mut buf = mapping;
+                buf[0] = version;
+                buf[1] = opcode;
+                let valid = version == 0 && (128..=130).contains(&opcode);
+                assert_eq!(decode_response(&buf).is_ok(), valid);
+            }
+        }
+        // requests
+        assert_eq!(
+            decode_response(&Natpmp::build_public_address_request()),
+            Err(Error::NATPMP_ERR_NOTARESPONSE)
+        );
+        assert_eq!(
+            decode_response(&Natpmp::build_destroy_all_request(Protocol::TCP)),
+            Err(Error::NATPMP_ERR_NOTARESPONSE)
+        );
+        // unknown result codes
+        for code in [6u16, 0x00ff, 0xffff] {
+            let mut buf = gateway;
+            buf[2..4].copy_from_slice(&code.to_be_bytes());
+            assert_eq!(decode_response(&buf), Err(Error::NATPMP_ERR_UNDEFINEDERROR));
+        }
+        // oversized
+        let mut buf = vec![0xffu8; NATPMP_RECV_BUFFER_SIZE];
+        buf[..12].copy_from_slice(&gateway);
+        assert_eq!(
+            decode_response_with_extra(&buf).unwrap().1.len(),
+            NATPMP_RECV_BUFFER_SIZE - 12
+        );
+    }
+
+    #[test]
+    fn test_vectors() {
+        let mapping = |protocol, private_port, public_port, lifetime| MappingResponse {
+            protocol,
+            epoch: 0x0102_0304,
+            private_port,
+            public_port,
+            lifetime: Duration::from_secs(lifetime),
+        };
+        // requests, rfc6886 section 3.2 and 3.3
+        assert_eq!(Natpmp::build_public_address_request(), [0x00, 0x00]);
+        assert_eq!(
+            Natpmp::build_mapping_request(Protocol::UDP, 5353, 0, 7200).unwrap(),
+            [0x00, 0x01, 0x00, 0x00, 0x14, 0xe9, 0x00, 0x00, 0x00, 0x00, 0x1c, 0x20]
+        );
+        assert_eq!(
+            Natpmp::build_mapping_request(Protocol::TCP, 22, 2222, 0xfedc_ba98).unwrap(),
+            [0x00, 0x02, 0x00, 0x00, 0x00, 0x16, 0x08, 0xae, 0xfe, 0xdc, 0xba, 0x98]
+        );
+        // responses, rfc6886 section 3.2, 3.3 and 3.5
+        let vectors: &[(&[u8], Result<Response>)] = &[
+            (
+                &[
+                    0x00, 0x80, 0x00, 0x00, 0x01, 0x02, 0x03, 0x04, 0xc6, 0x33, 0x64, 0x01,
+                ],
+                Ok(Response::Gateway(GatewayResponse {
+                    epoch: 0x0102_0304,
+                    public_address: Ipv4Addr::new(198, 51, 100, 1),
+                })),
+            ),
+            (
+                &[
+                    0x00, 0x81, 0x00, 0x00, 0x01, 0x02, 0x03, 0x04, 0x14, 0xe9, 0xc3, 0x50, 0x00,
+                    0x00, 0x1c, 0x20,
+                ],
+                Ok(Response::UDP(mapping(Protocol::UDP, 5353, 50000, 7200))),
+            ),
+            (
+                &[
+                    0x00, 0x82, 0x00, 0x00, 0x01, 0x02, 0x03, 0x04, 0x00, 0x16, 0x08, 0xae, 0x00,
+                    0x00, 0x0e, 0x10,
+                ],
+                Ok(Response::TCP(mapping(Protocol::TCP, 22, 2222, 3600))),
+            ),
+            (
+                &[
+                    0x00, 0x80, 0x00, 0x01, 0x01, 0x02, 0x03, 0x04, 0x00, 0x00, 0x00, 0x00,
+                ],
+                Err(Error::NATPMP_ERR_UNSUPPORTEDVERSION),
+            ),
+            (
+                &[
+                    0x00, 0x82, 0x00, 0x02, 0x01, 0x02, 0x03, 0x04, 0x00, 0x16, 0x00, 0x00, 0x00,
+                    0x00, 0x00, 0x00,
+                ],
+                Err(Error::NATPMP_ERR_MAPPINGDISABLED),
+            ),
+            (
+                &[
+                    0x00, 0x80, 0x00, 0x02, 0x01, 0x02, 0x03, 0x04, 0x00, 0x00, 0x00, 0x00,
+                ],
+                Err(Error::NATPMP_ERR_NOTAUTHORIZED),
+            ),
+            (
+                &[
+                    0x00, 0x80, 0x00, 0x03, 0x01, 0x02, 0x03, 0x04, 0x00, 0x00, 0x00, 0x00,
+                ],
+                Err(Error::NATPMP_ERR_NETWORKFAILURE),
+            ),
+            (
+                &[
+                    0x00, 0x81, 0x00, 0x04, 0x01, 0x02, 0x03, 0x04, 0x14, 0xe9, 0x00, 0x00, 0x00,
+                    0x00, 0x00, 0x00,
+                ],
+                Err(Error::NATPMP_ERR_OUTOFRESOURCES),
+            ),
+            (
+                &[0x00, 0x83, 0x00, 0x05, 0x01, 0x02, 0x03, 0x04],
+                Err(Error::NATPMP_ERR_UNSUPPORTEDOPCODE),
+            ),
+            (
+                &[
+                    0x01, 0x80, 0x00, 0x00, 0x01, 0x02, 0x03, 0x04, 0x00, 0x00, 0x00, 0x00,
+                ],
+                Err(Error::NATPMP_ERR_UNSUPPORTEDVERSION),
+            ),
+        ];
+        for (buf, expected) in vectors {
+            assert_eq!(&decode_response(buf), expected, "vector {:02x?}", buf);
+        }
+    }
+
+    #[test]
+    fn test_jitter() -> Result<()> {
+        let mut n = Natpmp::new_with("192.168.0.1".parse().unwrap())?;
+        assert_eq!(n.retransmission_delay(1), Duration::from_millis(250));
+        assert_eq!(n.retransmission_delay(3), Duration::from_millis(1000));
+        assert_eq!(n.set_jitter(1.5), Err(Error::NATPMP_ERR_INVALIDARGS));
+        n.set_jitter(0.5)?;
+        for _ in 0..100 {
+            let delay = n.retransmission_delay(3);
+            assert!(delay >= Duration::from_millis(500));
+            assert!(delay <= Duration::from_millis(1500));
+        }
+        // reproducible once seeded
+        let mut other = Natpmp::new_with("192.168.0.1".parse().unwrap())?;
+        other.set_jitter(0.5)?;
+        n.set_jitter_seed(6886);
+        other.set_jitter_seed(6886);
+        for try_number in 1..=9 {
+            assert_eq!(
+                n.retransmission_delay(try_number),
+                other.retransmission_delay(try_number)
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_ensure_mapping() -> Result<()> {
+        let gateway = Ipv4Addr::new(127, 0, 0, 2);
+        // 8080 already mapped to 9090
+        let mut mapped = 9090;
+        let g = fake_gateway(gateway, 3, move |request| {
+            let lifetime = u32::from_be_bytes([request[8], request[9], request[10], request[11]]);
+            if lifetime == 0 {
+                mapped = 8080;
+                return mapping_response(request, 0, 0);
             }
-            Err(_) => {}
+            mapping_response(request, mapped, lifetime)
+        });
+        let mut n = Natpmp::new_with(gateway)?;
+        let start = Instant::now();
+        let m = n.ensure_mapping(Protocol::TCP, 8080, 8080, 60)?;
+        assert_eq!(m.public_port(), 8080);
+        assert_eq!(m.lifetime(), &Duration::from_secs(60));
+        // backed off before asking again
+        assert!(start.elapsed() >= Duration::from_millis(NATPMP_MIN_WAIT));
+        let requests = g.join().unwrap();
+        assert_eq!(
+            requests,
+            vec![
+                Natpmp::build_mapping_request(Protocol::TCP, 8080, 8080, 60)
+                    .unwrap()
+                    .to_vec(),
+                Natpmp::build_mapping_request(Protocol::TCP, 8080, 0, 0)
+                    .unwrap()
+                    .to_vec(),
+                Natpmp::build_mapping_request(Protocol::TCP, 8080, 8080, 60)
+                    .unwrap()
+                    .to_vec(),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_local_port() -> Result<()> {
+        let gateway = "192.168.0.1".parse().unwrap();
+        let n = Natpmp::new_with(gateway)?;
+        let port = n.s.local_addr().unwrap().port();
+        assert_eq!(
+            Natpmp::new_with_local_port(gateway, port).unwrap_err(),
+            Error::NATPMP_ERR_ADDRINUSE
+        );
+        drop(n);
+        let n = Natpmp::new_with_local_port(gateway, port)?;
+        assert_eq!(n.local_addr()?.port(), port);
+        Ok(())
+    }
+
+    #[test]
+    fn test_skip_garbage() -> Result<()> {
+        let gateway = Ipv4Addr::new(127, 0, 0, 6);
+        let g = UdpSocket::bind((gateway, NATPMP_PORT)).unwrap();
+        let t = thread::spawn(move || {
+            let mut buf = [0u8; 12];
+            let (_, addr) = g.recv_from(&mut buf).unwrap();
+            g.send_to(b"garbage", addr).unwrap();
+            g.send_to(&[0, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 7], addr)
+                .unwrap();
+        });
+        let mut n = Natpmp::new_with(gateway)?;
+        n.send_public_address_request()?;
+        t.join().unwrap();
+        let r = n.wait_response()?;
+        assert!(matches!(r, Response::Gateway(_)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unconnected() -> Result<()> {
+        let first = Ipv4Addr::new(127, 0, 0, 9);
+        let second = Ipv4Addr::new(127, 0, 0, 10);
+        let g1 = fake_gateway(first, 2, |request| match request.len() {
+            2 => vec![0, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 7],
+            _ => mapping_response(request, 1, 60),
+        });
+        let g2 = fake_gateway(second, 1, |request| mapping_response(request, 2, 60));
+        let n = Natpmp::new_with_mode(first, 0, SocketMode::Unconnected)?;
+        assert_eq!(n.mode(), SocketMode::Unconnected);
+        let timeout = Duration::from_secs(1);
+
+        // a stray datagram is skipped
+        let port = n.s.local_addr().unwrap().port();
+        UdpSocket::bind((second, 0))
+            .unwrap()
+            .send_to(&[0, 128, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], (first, port))
+            .unwrap();
+        n.send_port_mapping_request_to(second, Protocol::UDP, 7001, 7001, 60)?;
+        match n.read_response_from(second, timeout)? {
+            Response::UDP(m) => assert_eq!(m.public_port(), 2),
+            _ => panic!("Not a udp mapping response"),
         }
+        n.send_public_address_request_to(first)?;
+        n.send_port_mapping_request_to(first, Protocol::UDP, 7001, 7001, 60)?;
+        assert!(n.read_response_from(first, timeout).is_ok());
+        assert!(n.read_response_from(first, timeout).is_ok());
+        assert_eq!(
+            n.read_response_from(first, Duration::from_millis(10)),
+            Err(Error::NATPMP_TRYAGAIN)
+        );
+        g1.join().unwrap();
+        g2.join().unwrap();
+
+        let connected = Natpmp::new_with(first)?;
+        assert_eq!(
+            connected.send_public_address_request_to(second),
+            Err(Error::NATPMP_ERR_INVALIDARGS)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unconnected_local_port() -> Result<()> {
+        let gateway = Ipv4Addr::new(127, 0, 0, 25);
+        let g = UdpSocket::bind((gateway, NATPMP_PORT)).unwrap();
+        let port = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let n = Natpmp::new_with_mode(gateway, port, SocketMode::Unconnected)?;
+        n.send_public_address_request_to(gateway)?;
+        let mut buf = [0u8; 12];
+        let (_, addr) = g.recv_from(&mut buf).unwrap();
+        assert_eq!(addr.port(), port);
+        g.send_to(&[0, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 7], addr)
+            .unwrap();
+        assert!(matches!(
+            n.read_response_from(gateway, Duration::from_secs(1))?,
+            Response::Gateway(_)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_map_bound_port() -> Result<()> {
+        let gateway = Ipv4Addr::new(127, 0, 0, 15);
+        let g = fake_gateway(gateway, 2, |request| {
+            let port = u16::from_be_bytes([request[4], request[5]]);
+            mapping_response(request, port, 60)
+        });
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let tcp_port = listener.local_addr().unwrap().port();
+        let udp_port = socket.local_addr().unwrap().port();
+        let mut n = Natpmp::new_with(gateway)?;
+        let m = n.map_listener(&listener, 60)?;
+        assert_eq!((m.protocol(), m.private_port()), (Protocol::TCP, tcp_port));
+        let m = n.map_udp_socket(&socket, 60)?;
+        assert_eq!((m.protocol(), m.private_port()), (Protocol::UDP, udp_port));
+        assert_eq!(
+            g.join().unwrap(),
+            vec![
+                Natpmp::build_mapping_request(Protocol::TCP, tcp_port, tcp_port, 60)
+                    .unwrap()
+                    .to_vec(),
+                Natpmp::build_mapping_request(Protocol::UDP, udp_port, udp_port, 60)
+                    .unwrap()
+                    .to_vec(),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_destroy_mapping_both() -> Result<()> {
+        let gateway = Ipv4Addr::new(127, 0, 0, 16);
+        let g = fake_gateway(gateway, 2, |request| {
+            let mut response = mapping_response(request, 0, 0);
+            if request[1] == 1 {
+                // udp refused
+                response[3] = 2;
+            }
+            response
+        });
+        let mut n = Natpmp::new_with(gateway)?;
+        assert_eq!(
+            n.destroy_mapping_both(7200),
+            Err(Error::NATPMP_ERR_MAPPINGDISABLED)
+        );
+        // tcp was destroyed all the same, with a suggested public port of 0
+        assert_eq!(
+            g.join().unwrap(),
+            vec![
+                Natpmp::build_mapping_request(Protocol::UDP, 7200, 0, 0)
+                    .unwrap()
+                    .to_vec(),
+                Natpmp::build_mapping_request(Protocol::TCP, 7200, 0, 0)
+                    .unwrap()
+                    .to_vec(),
+            ]
+        );
+
+        // both refused, the udp error wins
+        let gateway = Ipv4Addr::new(127, 0, 0, 31);
+        let g = fake_gateway(gateway, 2, |request| {
+            let mut response = mapping_response(request, 0, 0);
+            response[3] = if request[1] == 1 { 2 } else { 4 };
+            response
+        });
+        let mut n = Natpmp::new_with(gateway)?;
+        assert_eq!(
+            n.destroy_mapping_both(7201),
+            Err(Error::NATPMP_ERR_MAPPINGDISABLED)
+        );
+        assert_eq!(g.join().unwrap().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fixed_lifetime() -> Result<()> {
+        let gateway = Ipv4Addr::new(127, 0, 0, 17);
+        // a router granting an hour whatever the request
+        let _g = fake_gateway(gateway, 1, |request| mapping_response(request, 7300, 3600));
+        let mut n = Natpmp::new_with(gateway)?;
+        let m = n.map_port(Protocol::TCP, 7300, 7300, 60)?;
+        assert_eq!(m.lifetime(), &Duration::from_secs(3600));
+        assert!(!m.honored_lifetime(60));
+        assert!(m.honored_lifetime(3600));
+        let received = Instant::now();
+        assert_eq!(m.renew_at(received), received + Duration::from_secs(1800));
+        Ok(())
+    }
+
+    #[test]
+    fn test_lossy_gateway() -> Result<()> {
+        let gateway = Ipv4Addr::new(127, 0, 0, 21);
+        let g = lossy_gateway(gateway, 2);
+        let mut n = Natpmp::new_with(gateway)?;
+        assert_eq!(
+            n.map_port(Protocol::UDP, 9000, 9000, 60)?.public_port(),
+            9000
+        );
+        assert_eq!(g.join().unwrap(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_public_port_unavailable() -> Result<()> {
+        let gateway = Ipv4Addr::new(127, 0, 0, 24);
+        let g = fake_gateway(gateway, 5, |request| {
+            let private_port = u16::from_be_bytes([request[4], request[5]]);
+            let public_port = u16::from_be_bytes([request[6], request[7]]);
+            let mut response = mapping_response(request, 7501, 60);
+            // 7500 is taken, 7600 is refused whatever the public port
+            if public_port == 7500 || private_port == 7600 {
+                response[3] = 4;
+            }
+            response
+        });
+        let mut n = Natpmp::new_with(gateway)?;
+        let e = n
+            .map_port_strict(Protocol::TCP, 7500, 7500, 60)
+            .unwrap_err();
+        assert_eq!(e, Error::NATPMP_ERR_PUBLICPORTUNAVAILABLE);
+        assert!(e.is_port_unavailable());
+        assert_eq!(
+            n.map_port_strict(Protocol::TCP, 7600, 7600, 60),
+            Err(Error::NATPMP_ERR_OUTOFRESOURCES)
+        );
+        let requests = g.join().unwrap();
+        // probed with any public port, then destroyed
+        assert_eq!(
+            requests[1],
+            Natpmp::build_mapping_request(Protocol::TCP, 7500, 0, 60).unwrap()
+        );
+        assert_eq!(
+            requests[2],
+            Natpmp::build_mapping_request(Protocol::TCP, 7500, 0, 0).unwrap()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_refresh_request() -> Result<()> {
+        let gateway = Ipv4Addr::new(127, 0, 0, 12);
+        let g = fake_gateway(gateway, 2, |request| mapping_response(request, 7101, 60));
+        let mut n = Natpmp::new_with(gateway)?;
+        let m = n.map_port(Protocol::UDP, 7100, 0, 60)?;
+        assert_eq!(m.protocol(), Protocol::UDP);
+        n.send_request(m.refresh_request(120))?;
+        assert!(matches!(n.wait_response()?, Response::UDP(_)));
+        // the granted port is requested
+        assert_eq!(
+            g.join().unwrap()[1],
+            Natpmp::build_mapping_request(Protocol::UDP, 7100, 7101, 120).unwrap()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_private_port_zero() -> Result<()> {
+        let gateway = Ipv4Addr::new(127, 0, 0, 11);
+        let g = fake_gateway(gateway, 1, |request| mapping_response(request, 0, 0));
+        let mut n = Natpmp::new_with(gateway)?;
+        assert_eq!(
+            n.map_port(Protocol::TCP, 0, 8080, 60),
+            Err(Error::NATPMP_ERR_INVALIDPRIVATEPORT)
+        );
+        assert_eq!(
+            n.send_port_mapping_request(Protocol::TCP, 0, 0, 0),
+            Err(Error::NATPMP_ERR_INVALIDPRIVATEPORT)
+        );
+        n.destroy_all_mappings(Protocol::TCP)?;
+        // only the destroy request was sent
+        assert_eq!(
+            g.join().unwrap(),
+            vec![Natpmp::build_destroy_all_request(Protocol::TCP).to_vec()]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_drain() -> Result<()> {
+        let gateway = Ipv4Addr::new(127, 0, 0, 8);
+        let g = UdpSocket::bind((gateway, NATPMP_PORT)).unwrap();
+        let t = thread::spawn(move || {
+            let mut buf = [0u8; 12];
+            // the first reply is duplicated
+            let (n, addr) = g.recv_from(&mut buf).unwrap();
+            g.send_to(&mapping_response(&buf[..n], 1, 60), addr)
+                .unwrap();
+            g.send_to(&mapping_response(&buf[..n], 1, 60), addr)
+                .unwrap();
+            let (n, addr) = g.recv_from(&mut buf).unwrap();
+            g.send_to(&mapping_response(&buf[..n], 2, 60), addr)
+                .unwrap();
+        });
+        let mut n = Natpmp::new_with(gateway)?;
+        assert!(!n.drain_before_request());
+        n.set_drain_before_request(true);
+        assert_eq!(n.map_port(Protocol::TCP, 7000, 1, 60)?.public_port(), 1);
+        // wait for the duplicate
+        n.wait_readable(Duration::from_secs(1))?;
+        assert_eq!(n.map_port(Protocol::TCP, 7000, 2, 60)?.public_port(), 2);
+        t.join().unwrap();
+        assert_eq!(n.drain()?, 0);
         Ok(())
     }
 }
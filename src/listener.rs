@@ -0,0 +1,387 @@
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time::Duration;
+
+use socket2::{Domain, Socket, Type};
+
+use crate::{
+    decode_response, get_default_gateway, EpochTracker, Error, GatewayResponse, Response, Result,
+    NATPMP_PORT, NATPMP_RECV_BUFFER_SIZE,
+};
+
+/// Multicast group gateways announce to, as defined by rfc6886.
+pub const NATPMP_ANNOUNCE_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 1);
+
+/// Port gateways announce to, as defined by rfc6886.
+pub const NATPMP_ANNOUNCE_PORT: u16 = 5350;
+
+/// Listener for the gateway's multicast address announcements.
+///
+/// A gateway announces its public address when it boots or when the address changes, as a
+/// burst of up to 10 public address responses sent to
+/// [`NATPMP_ANNOUNCE_ADDR`](constant.NATPMP_ANNOUNCE_ADDR.html):[`NATPMP_ANNOUNCE_PORT`](constant.NATPMP_ANNOUNCE_PORT.html).
+/// Announcements not sent by the gateway are ignored.
+///
+/// # Examples
+/// ```no_run
+/// use natpmp::*;
+///
+/// # fn main() -> Result<()> {
+/// let mut listener = AnnouncementListener::new()?;
+/// let announcement = listener.wait_for_reboot()?;
+/// println!("gateway rebooted, public address {}", announcement.public_address());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct AnnouncementListener {
+    s: UdpSocket,
+    gateway: Ipv4Addr,
+    interface: Ipv4Addr,
+    tracker: EpochTracker,
+}
+
+impl AnnouncementListener {
+    /// Listen for announcements of the default gateway.
+    ///
+    /// # Errors
+    /// See [`get_default_gateway`](fn.get_default_gateway.html) and [`AnnouncementListener::new_with`](struct.AnnouncementListener.html#method.new_with).
+    pub fn new() -> Result<AnnouncementListener> {
+        let gateway = get_default_gateway()?;
+        AnnouncementListener::new_with(gateway)
+    }
+
+    /// Listen for announcements of a specified gateway.
+    ///
+    /// # Errors
+    /// See [`AnnouncementListenerBuilder::bind`](struct.AnnouncementListenerBuilder.html#method.bind).
+    pub fn new_with(gateway: Ipv4Addr) -> Result<AnnouncementListener> {
+        AnnouncementListener::builder().bind(gateway)
+    }
+
+    /// Builder to configure the listener socket.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let listener = AnnouncementListener::builder()
+    ///     .multicast_loop(false)
+    ///     .bind(get_default_gateway()?)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn builder() -> AnnouncementListenerBuilder {
+        AnnouncementListenerBuilder::default()
+    }
+
+    /// Listen for announcements of any gateway, to learn the gateway with
+    /// [`await_gateway`](struct.AnnouncementListener.html#method.await_gateway).
+    ///
+    /// # Errors
+    /// See [`AnnouncementListenerBuilder::bind_any`](struct.AnnouncementListenerBuilder.html#method.bind_any).
+    pub fn discover() -> Result<AnnouncementListener> {
+        AnnouncementListener::builder().bind_any()
+    }
+
+    /// NAT-PMP gateway address, unspecified until a listener of any gateway learns it.
+    pub fn gateway(&self) -> &Ipv4Addr {
+        &self.gateway
+    }
+
+    /// Address of the local interface the announcement group was joined on.
+    pub fn interface(&self) -> &Ipv4Addr {
+        &self.interface
+    }
+
+    /// Set the timeout of [`recv`](struct.AnnouncementListener.html#method.recv), `None` blocks forever.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_INVALIDARGS`](enum.Error.html#variant.NATPMP_ERR_INVALIDARGS) for a zero timeout
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.s
+            .set_read_timeout(timeout)
+            .map_err(|_| Error::NATPMP_ERR_INVALIDARGS)
+    }
+
+    /// Seed reboot detection with an epoch received from the gateway.
+    ///
+    /// Use the epoch of the last response from the gateway, so that
+    /// [`wait_for_reboot`](struct.AnnouncementListener.html#method.wait_for_reboot) only fires
+    /// for a reboot after it.
+    pub fn observe_epoch(&mut self, epoch: u32) {
+        self.tracker.observe(epoch);
+    }
+
+    /// Receive the next announcement of the gateway.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_TRYAGAIN`](enum.Error.html#variant.NATPMP_TRYAGAIN) if the read timeout elapsed
+    /// * [`Error::NATPMP_ERR_RECVFROM`](enum.Error.html#variant.NATPMP_ERR_RECVFROM)
+    pub fn recv(&mut self) -> Result<GatewayResponse> {
+        self.recv_from().map(|(gr, _)| gr)
+    }
+
+    /// Receive the next announcement of the gateway, with the address it was sent from.
+    ///
+    /// Before the gateway is learned, announcements of any gateway are received.
+    ///
+    /// # Errors
+    /// See [`recv`](struct.AnnouncementListener.html#method.recv).
+    pub fn recv_from(&mut self) -> Result<(GatewayResponse, Ipv4Addr)> {
+        let mut buf = [0u8; NATPMP_RECV_BUFFER_SIZE];
+        loop {
+            let (n, sockaddr) = self.s.recv_from(&mut buf).map_err(|e| match e.kind() {
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => {
+                    Error::NATPMP_TRYAGAIN
+                }
+                _ => Error::NATPMP_ERR_RECVFROM,
+            })?;
+            let source = match sockaddr {
+                SocketAddr::V4(addr) if addr.port() == NATPMP_PORT => *addr.ip(),
+                _ => continue,
+            };
+            if !self.gateway.is_unspecified() && source != self.gateway {
+                continue;
+            }
+            if let Ok(Response::Gateway(gr)) = decode_response(&buf[..n]) {
+                return Ok((gr, source));
+            }
+        }
+    }
+
+    /// Wait up to `timeout` for an announcement and return the gateway which sent it.
+    ///
+    /// A listener of any gateway then only listens to this one. Discovers the gateway passively,
+    /// without [`get_default_gateway`](fn.get_default_gateway.html), on networks where
+    /// gateways announce their address.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_INVALIDARGS`](enum.Error.html#variant.NATPMP_ERR_INVALIDARGS) for a zero timeout
+    /// * See [`recv`](struct.AnnouncementListener.html#method.recv).
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use std::time::Duration;
+    /// use natpmp::*;
+    ///
+    /// # fn main() -> Result<()> {
+    /// let mut listener = AnnouncementListener::discover()?;
+    /// let gateway = listener.await_gateway(Duration::from_secs(30))?;
+    /// let n = Natpmp::new_with(gateway)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn await_gateway(&mut self, timeout: Duration) -> Result<Ipv4Addr> {
+        let previous = self
+            .s
+            .read_timeout()
+            .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?;
+        self.set_read_timeout(Some(timeout))?;
+        let received = self.recv_from();
+        self.set_read_timeout(previous)?;
+        let (gr, gateway) = received?;
+        self.gateway = gateway;
+        self.tracker.observe(gr.epoch());
+        Ok(gateway)
+    }
+
+    /// Block until the gateway announces it has rebooted.
+    ///
+    /// An announcement indicates a reboot when its epoch went back compared to the last known
+    /// epoch, see [`EpochTracker`](struct.EpochTracker.html). Without a known epoch the first
+    /// announcement is reported. The rest of the announcement burst is consistent with the new
+    /// epoch, so a reboot is reported once. Mappings should be re-created afterwards.
+    ///
+    /// # Errors
+    /// See [`recv`](struct.AnnouncementListener.html#method.recv).
+    pub fn wait_for_reboot(&mut self) -> Result<GatewayResponse> {
+        loop {
+            let gr = self.recv()?;
+            let first = self.tracker.last_epoch().is_none();
+            if self.tracker.observe(gr.epoch()) || first {
+                return Ok(gr);
+            }
+        }
+    }
+}
+
+/// Builder of an [`AnnouncementListener`](struct.AnnouncementListener.html).
+///
+/// Options not set keep the system defaults.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct AnnouncementListenerBuilder {
+    multicast_ttl: Option<u32>,
+    multicast_loop: Option<bool>,
+    multicast_interface: Option<Ipv4Addr>,
+}
+
+impl AnnouncementListenerBuilder {
+    /// Set `IP_MULTICAST_TTL` of the listener socket.
+    pub fn multicast_ttl(mut self, ttl: u32) -> AnnouncementListenerBuilder {
+        self.multicast_ttl = Some(ttl);
+        self
+    }
+
+    /// Set `IP_MULTICAST_LOOP` of the listener socket.
+    ///
+    /// Disabling loopback is required on some bridged interfaces to receive announcements.
+    pub fn multicast_loop(mut self, enabled: bool) -> AnnouncementListenerBuilder {
+        self.multicast_loop = Some(enabled);
+        self
+    }
+
+    /// Set the address of the local interface to join the announcement group on.
+    ///
+    /// Defaults to the interface routing to the gateway. Set it when that route is not the
+    /// network of the gateway, e.g. when a VPN routes everything.
+    pub fn multicast_interface(mut self, interface: Ipv4Addr) -> AnnouncementListenerBuilder {
+        self.multicast_interface = Some(interface);
+        self
+    }
+
+    /// Bind the listener and join the announcement group, for announcements of `gateway`.
+    ///
+    /// The socket is bound with `SO_REUSEADDR`, so several listeners can run on one host.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_SOCKETERROR`](enum.Error.html#variant.NATPMP_ERR_SOCKETERROR)
+    pub fn bind(self, gateway: Ipv4Addr) -> Result<AnnouncementListener> {
+        let interface = self
+            .multicast_interface
+            .unwrap_or_else(|| route_interface(gateway));
+        self.bind_interface(gateway, interface)
+    }
+
+    /// Bind the listener and join the announcement group, for announcements of any gateway.
+    ///
+    /// Without [`multicast_interface`](struct.AnnouncementListenerBuilder.html#method.multicast_interface)
+    /// the system chooses the interface.
+    ///
+    /// # Errors
+    /// See [`bind`](struct.AnnouncementListenerBuilder.html#method.bind).
+    pub fn bind_any(self) -> Result<AnnouncementListener> {
+        let interface = self.multicast_interface.unwrap_or(Ipv4Addr::UNSPECIFIED);
+        self.bind_interface(Ipv4Addr::UNSPECIFIED, interface)
+    }
+
+    fn bind_interface(
+        self,
+        gateway: Ipv4Addr,
+        interface: Ipv4Addr,
+    ) -> Result<AnnouncementListener> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(socket2::Protocol::UDP))
+            .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?;
+        socket
+            .set_reuse_address(true)
+            .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?;
+        if let Some(ttl) = self.multicast_ttl {
+            socket
+                .set_multicast_ttl_v4(ttl)
+                .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?;
+        }
+        if let Some(enabled) = self.multicast_loop {
+            socket
+                .set_multicast_loop_v4(enabled)
+                .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?;
+        }
+        let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, NATPMP_ANNOUNCE_PORT);
+        socket
+            .bind(&addr.into())
+            .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?;
+        let s: UdpSocket = socket.into();
+        s.join_multicast_v4(&NATPMP_ANNOUNCE_ADDR, &interface)
+            .map_err(|_| Error::NATPMP_ERR_SOCKETERROR)?;
+        Ok(AnnouncementListener {
+            s,
+            gateway,
+            interface,
+            tracker: EpochTracker::new(),
+        })
+    }
+}
+
+/// Address of the local interface routing to `gateway`, unspecified to let the system choose
+/// if there is no route.
+fn route_interface(gateway: Ipv4Addr) -> Ipv4Addr {
+    UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .and_then(|s| {
+            s.connect((gateway, NATPMP_PORT))?;
+            s.local_addr()
+        })
+        .ok()
+        .and_then(|addr| match addr {
+            SocketAddr::V4(addr) => Some(*addr.ip()),
+            SocketAddr::V6(_) => None,
+        })
+        .unwrap_or(Ipv4Addr::UNSPECIFIED)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wait_for_reboot() -> Result<()> {
+        let gateway = Ipv4Addr::LOCALHOST;
+        let mut listener = AnnouncementListener::builder()
+            .multicast_ttl(4)
+            .multicast_loop(false)
+            .bind(gateway)?;
+        assert_eq!(listener.s.multicast_ttl_v4().unwrap(), 4);
+        assert!(!listener.s.multicast_loop_v4().unwrap());
+        // the interface routing to the gateway
+        assert_eq!(listener.interface(), &Ipv4Addr::LOCALHOST);
+        listener.set_read_timeout(Some(Duration::from_secs(1)))?;
+        listener.observe_epoch(1000);
+
+        let g = UdpSocket::bind((gateway, NATPMP_PORT)).unwrap();
+        let announce = |epoch: u32| {
+            let mut buf = [0u8, 128, 0, 0, 0, 0, 0, 0, 203, 0, 113, 7];
+            buf[4..8].copy_from_slice(&epoch.to_be_bytes());
+            g.send_to(&buf, (gateway, NATPMP_ANNOUNCE_PORT)).unwrap();
+        };
+        // not a reboot, then a burst after a reboot
+        announce(1001);
+        announce(0);
+        announce(0);
+        announce(1);
+        // from somewhere else
+        UdpSocket::bind((gateway, 0))
+            .unwrap()
+            .send_to(
+                &[0u8, 128, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+                (gateway, NATPMP_ANNOUNCE_PORT),
+            )
+            .unwrap();
+
+        let gr = listener.wait_for_reboot()?;
+        assert_eq!(gr.epoch(), 0);
+        assert_eq!(gr.public_address(), &Ipv4Addr::new(203, 0, 113, 7));
+        assert_eq!(listener.wait_for_reboot(), Err(Error::NATPMP_TRYAGAIN));
+
+        // listeners share the port, so not in a test of its own
+        let listener = AnnouncementListener::builder()
+            .multicast_interface(Ipv4Addr::UNSPECIFIED)
+            .bind(gateway)?;
+        assert_eq!(listener.interface(), &Ipv4Addr::UNSPECIFIED);
+
+        // passive discovery, announcements not from the nat-pmp port are ignored
+        drop(listener);
+        let mut listener = AnnouncementListener::discover()?;
+        assert!(listener.gateway().is_unspecified());
+        let discovered = Ipv4Addr::new(127, 0, 0, 18);
+        let announcement = [0u8, 128, 0, 0, 0, 0, 0, 5, 203, 0, 113, 8];
+        UdpSocket::bind((discovered, 0))
+            .unwrap()
+            .send_to(&announcement, (gateway, NATPMP_ANNOUNCE_PORT))
+            .unwrap();
+        UdpSocket::bind((discovered, NATPMP_PORT))
+            .unwrap()
+            .send_to(&announcement, (gateway, NATPMP_ANNOUNCE_PORT))
+            .unwrap();
+        assert_eq!(listener.await_gateway(Duration::from_secs(1))?, discovered);
+        assert_eq!(listener.gateway(), &discovered);
+        Ok(())
+    }
+}
@@ -0,0 +1,379 @@
+use std::result;
+use std::time::SystemTime;
+
+use crate::{
+    lifetime_secs, Error, MappingHandle, MappingResponse, Natpmp, Protocol, Response, Result,
+};
+
+/// Keeps track of the mappings created through it, to release them on shutdown.
+///
+/// # Examples
+/// ```no_run
+/// use natpmp::*;
+///
+/// # fn main() -> Result<()> {
+/// let mut manager = MappingManager::new(Natpmp::new()?);
+/// manager.map_port(Protocol::TCP, 8080, 8080, 3600)?;
+/// manager.map_port(Protocol::UDP, 8080, 8080, 3600)?;
+/// // ...
+/// if let Err(failures) = manager.shutdown() {
+///     for (handle, e) in failures {
+///         eprintln!("failed to release {:?} {}: {}", handle.protocol(), handle.private_port(), e);
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct MappingManager {
+    client: Natpmp,
+    mappings: Vec<MappingHandle>,
+}
+
+impl MappingManager {
+    /// Manage mappings created with `client`.
+    pub fn new(client: Natpmp) -> MappingManager {
+        MappingManager {
+            client,
+            mappings: Vec::new(),
+        }
+    }
+
+    /// Underlying NAT-PMP client.
+    pub fn client(&mut self) -> &mut Natpmp {
+        &mut self.client
+    }
+
+    /// Tracked mappings.
+    pub fn mappings(&self) -> &[MappingHandle] {
+        &self.mappings
+    }
+
+    /// Create or refresh a mapping and track it, see [`Natpmp::map_port`](struct.Natpmp.html#method.map_port).
+    pub fn map_port(
+        &mut self,
+        protocol: Protocol,
+        private_port: u16,
+        public_port: u16,
+        lifetime: u32,
+    ) -> Result<MappingResponse> {
+        let (handle, m) =
+            self.client
+                .map_port_handle(protocol, private_port, public_port, lifetime)?;
+        self.track(handle);
+        Ok(m)
+    }
+
+    /// Re-establish previously stored mappings and track them.
+    ///
+    /// The gateway's epoch is requested first with a public address request. A mapping is
+    /// tracked as stored if that epoch shows no reboot since it was established, see
+    /// [`EpochTracker`](struct.EpochTracker.html), and it is not due for renewal yet. Every
+    /// other mapping is requested again with its granted public port and lifetime, all of
+    /// them if the epoch could not be obtained. If the public port is no longer available the
+    /// gateway assigns another one, and `on_remap` is called with the stored and the new
+    /// handle so the advertised endpoint can be updated. Mappings which could not be
+    /// re-established are returned with the error and not tracked.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use natpmp::*;
+    ///
+    /// # fn stored() -> Vec<MappingHandle> { Vec::new() }
+    /// # fn main() -> Result<()> {
+    /// let mut manager = MappingManager::new(Natpmp::new()?);
+    /// let resumed = manager.resume(stored(), |old, new| {
+    ///     println!("port {} moved to {}", old.public_port(), new.public_port());
+    /// });
+    /// if let Err(failures) = resumed {
+    ///     eprintln!("{} mappings were lost", failures.len());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn resume<I, F>(
+        &mut self,
+        handles: I,
+        mut on_remap: F,
+    ) -> result::Result<(), Vec<(MappingHandle, Error)>>
+    where
+        I: IntoIterator<Item = MappingHandle>,
+        F: FnMut(&MappingHandle, &MappingHandle),
+    {
+        let epoch = self.gateway_epoch();
+        let now = SystemTime::now();
+        let mut failures = Vec::new();
+        for handle in handles {
+            let live = match epoch {
+                Some(epoch) => !handle.rebooted_since(epoch),
+                None => false,
+            };
+            if live && handle.established() + handle.renew_after() > now {
+                self.track(handle);
+                continue;
+            }
+            match handle.renew(&mut self.client) {
+                Ok(renewed) => {
+                    if renewed.public_port() != handle.public_port() {
+                        on_remap(&handle, &renewed);
+                    }
+                    self.track(renewed);
+                }
+                Err(e) => failures.push((handle, e)),
+            }
+        }
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(failures)
+        }
+    }
+
+    /// Current epoch of the gateway, `None` if it could not be obtained.
+    fn gateway_epoch(&mut self) -> Option<u32> {
+        self.client.send_public_address_request().ok()?;
+        match self.client.wait_response() {
+            Ok(Response::Gateway(gr)) => Some(gr.epoch()),
+            _ => None,
+        }
+    }
+
+    /// Renew every tracked mapping now, e.g. after a network change.
+    ///
+    /// Each mapping is requested again with its granted public port and lifetime. Returns the
+    /// handle each mapping was tracked with and the outcome of its renewal. Renewed mappings
+    /// are tracked with the newly granted port and lifetime, the others are left as they were.
+    pub fn renew_all(&mut self) -> Vec<(MappingHandle, Result<MappingResponse>)> {
+        let client = &mut self.client;
+        let mut outcomes = Vec::new();
+        for tracked in self.mappings.iter_mut() {
+            let renewed = lifetime_secs(*tracked.lifetime()).and_then(|lifetime| {
+                client.map_port_handle(
+                    tracked.protocol(),
+                    tracked.private_port(),
+                    tracked.public_port(),
+                    lifetime,
+                )
+            });
+            match renewed {
+                Ok((handle, m)) => {
+                    outcomes.push((std::mem::replace(tracked, handle), Ok(m)));
+                }
+                Err(e) => outcomes.push((tracked.clone(), Err(e))),
+            }
+        }
+        outcomes
+    }
+
+    /// Track `handle`, replacing the mapping of the same private port.
+    fn track(&mut self, handle: MappingHandle) {
+        self.mappings.retain(|tracked| {
+            (tracked.protocol(), tracked.private_port())
+                != (handle.protocol(), handle.private_port())
+        });
+        self.mappings.push(handle);
+    }
+
+    /// Destroy every tracked mapping, best-effort.
+    ///
+    /// Every release is attempted, mappings which failed to be released are returned with
+    /// the error and stay tracked. Usable from a `Drop` implementation, see
+    /// [`shutdown`](struct.MappingManager.html#method.shutdown) otherwise.
+    pub fn release_all(&mut self) -> result::Result<(), Vec<(MappingHandle, Error)>> {
+        let mut failures = Vec::new();
+        for handle in std::mem::take(&mut self.mappings) {
+            if let Err(e) = handle.destroy(&mut self.client) {
+                failures.push((handle.clone(), e));
+                self.mappings.push(handle);
+            }
+        }
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(failures)
+        }
+    }
+
+    /// Destroy every tracked mapping and drop the manager.
+    ///
+    /// Releases are best-effort, see [`release_all`](struct.MappingManager.html#method.release_all).
+    pub fn shutdown(mut self) -> result::Result<(), Vec<(MappingHandle, Error)>> {
+        self.release_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+    use crate::tests::{fake_gateway, mapping_response};
+
+    #[test]
+    fn test_shutdown() -> Result<()> {
+        let gateway = Ipv4Addr::new(127, 0, 0, 3);
+        let g = fake_gateway(gateway, 4, |request| {
+            let mut response = mapping_response(request, 5000, 60);
+            // refuse to release udp
+            if request[1] == 1 && request[8..12] == [0, 0, 0, 0] {
+                response[3] = 2;
+            }
+            response
+        });
+        let mut manager = MappingManager::new(Natpmp::new_with(gateway)?);
+        manager.map_port(Protocol::TCP, 5000, 5000, 60)?;
+        manager.map_port(Protocol::UDP, 5000, 5000, 60)?;
+        assert_eq!(manager.mappings().len(), 2);
+        let udp = manager.mappings()[1].clone();
+        assert_eq!(udp.protocol(), Protocol::UDP);
+        assert_eq!(
+            manager.shutdown(),
+            Err(vec![(udp, Error::NATPMP_ERR_MAPPINGDISABLED)])
+        );
+        let requests = g.join().unwrap();
+        assert_eq!(
+            requests[2],
+            Natpmp::build_mapping_request(Protocol::TCP, 5000, 0, 0).unwrap()
+        );
+        assert_eq!(
+            requests[3],
+            Natpmp::build_mapping_request(Protocol::UDP, 5000, 0, 0).unwrap()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_renew_all() -> Result<()> {
+        let gateway = Ipv4Addr::new(127, 0, 0, 23);
+        let mut requests = 0;
+        let g = fake_gateway(gateway, 4, move |request| {
+            requests += 1;
+            match (requests, request[1]) {
+                // the tcp mapping moves, udp fails
+                (3, _) => mapping_response(request, 7401, 120),
+                (4, _) => {
+                    let mut response = mapping_response(request, 0, 0);
+                    response[3] = 3;
+                    response
+                }
+                _ => mapping_response(request, 7400, 60),
+            }
+        });
+        let mut manager = MappingManager::new(Natpmp::new_with(gateway)?);
+        manager.map_port(Protocol::TCP, 7400, 7400, 60)?;
+        manager.map_port(Protocol::UDP, 7400, 7400, 60)?;
+        let before = manager.mappings().to_vec();
+        let outcomes = manager.renew_all();
+        assert_eq!(outcomes.len(), 2);
+        assert_eq!(outcomes[0].0, before[0]);
+        assert_eq!(outcomes[0].1.as_ref().map(|m| m.public_port()), Ok(7401));
+        assert_eq!(
+            outcomes[1],
+            (before[1].clone(), Err(Error::NATPMP_ERR_NETWORKFAILURE))
+        );
+        // tracked with the fresh grant, the failed one unchanged
+        assert_eq!(manager.mappings()[0].public_port(), 7401);
+        assert_eq!(manager.mappings()[0].lifetime(), &Duration::from_secs(120));
+        assert_eq!(manager.mappings()[1], before[1]);
+        let requests = g.join().unwrap();
+        assert_eq!(
+            requests[2],
+            Natpmp::build_mapping_request(Protocol::TCP, 7400, 7400, 60).unwrap()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_resume() -> Result<()> {
+        let gateway = Ipv4Addr::new(127, 0, 0, 13);
+        let g = fake_gateway(gateway, 4, |request| {
+            if request.len() == 2 {
+                // counting from 0 again, rebooted since the mappings were stored
+                return vec![0, 128, 0, 0, 0, 0, 0, 0, 203, 0, 113, 7];
+            }
+            // 7201 was taken meanwhile, 7202 is refused
+            match request[4..6] {
+                [0x1c, 0x21] => mapping_response(request, 7301, 60),
+                [0x1c, 0x22] => {
+                    let mut response = mapping_response(request, 0, 0);
+                    response[3] = 4;
+                    response
+                }
+                _ => mapping_response(request, 7200, 60),
+            }
+        });
+        let stored = |port| {
+            MappingHandle::from_parts(
+                Protocol::TCP,
+                port,
+                port,
+                Duration::from_secs(60),
+                100,
+                SystemTime::now(),
+            )
+        };
+        let mut manager = MappingManager::new(Natpmp::new_with(gateway)?);
+        let mut remapped = Vec::new();
+        let failures = manager
+            .resume(
+                vec![stored(7200), stored(7201), stored(7202)],
+                |old, new| remapped.push((old.public_port(), new.public_port())),
+            )
+            .unwrap_err();
+        assert_eq!(remapped, vec![(7201, 7301)]);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].0.private_port(), 7202);
+        assert_eq!(failures[0].1, Error::NATPMP_ERR_OUTOFRESOURCES);
+        let ports: Vec<u16> = manager.mappings().iter().map(|h| h.public_port()).collect();
+        assert_eq!(ports, vec![7200, 7301]);
+        g.join().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_resume_live() -> Result<()> {
+        let gateway = Ipv4Addr::new(127, 0, 0, 32);
+        let g = fake_gateway(gateway, 2, |request| {
+            if request.len() == 2 {
+                // no reboot since the mappings were stored
+                return vec![0, 128, 0, 0, 0, 0, 0x0e, 0x74, 203, 0, 113, 7];
+            }
+            mapping_response(request, 7500, 60)
+        });
+        let live = MappingHandle::from_parts(
+            Protocol::TCP,
+            7500,
+            7500,
+            Duration::from_secs(86400),
+            100,
+            SystemTime::now() - Duration::from_secs(3600),
+        );
+        // more than half its lifetime went by
+        let due = MappingHandle::from_parts(
+            Protocol::UDP,
+            7500,
+            7500,
+            Duration::from_secs(60),
+            100,
+            SystemTime::now() - Duration::from_secs(3600),
+        );
+        let mut manager = MappingManager::new(Natpmp::new_with(gateway)?);
+        manager.resume(vec![live.clone(), due], |_, _| {}).unwrap();
+        assert_eq!(manager.mappings()[0], live);
+        assert_eq!(manager.mappings()[1].protocol(), Protocol::UDP);
+        assert_eq!(manager.mappings()[1].epoch(), 0);
+        // the live mapping was not requested again
+        let requests = g.join().unwrap();
+        assert_eq!(
+            requests,
+            vec![
+                Natpmp::build_public_address_request().to_vec(),
+                Natpmp::build_mapping_request(Protocol::UDP, 7500, 7500, 60)
+                    .unwrap()
+                    .to_vec(),
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,134 @@
+use std::io;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use async_channel::{Receiver, Sender};
+use async_trait::async_trait;
+
+use crate::asynchronous::{new_natpmp_async_with, AsyncUdpSocket, NatpmpAsync};
+
+/// Socket tunnelling datagrams through channels, e.g. to a task relaying them to a gateway
+/// only reachable from another network namespace.
+///
+/// Each message is a datagram: those sent go to `outgoing` and those received come from
+/// `incoming`. The relay addresses the gateway, so the socket is always connected.
+/// Timeouts are runtime-agnostic. Closed channels fail with `io::ErrorKind::BrokenPipe`.
+///
+/// # Examples
+/// ```
+/// use natpmp::*;
+///
+/// # fn main() -> Result<()> {
+/// let (outgoing, relay_rx) = async_channel::unbounded::<Vec<u8>>();
+/// let (relay_tx, incoming) = async_channel::unbounded::<Vec<u8>>();
+/// // a relay task forwards `relay_rx` to the gateway and its replies to `relay_tx`
+/// let n = new_relay_natpmp(outgoing, incoming, "192.168.0.1".parse().unwrap());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct RelayUdpSocket {
+    outgoing: Sender<Vec<u8>>,
+    incoming: Receiver<Vec<u8>>,
+}
+
+impl RelayUdpSocket {
+    /// Create a socket sending to `outgoing` and receiving from `incoming`.
+    pub fn new(outgoing: Sender<Vec<u8>>, incoming: Receiver<Vec<u8>>) -> RelayUdpSocket {
+        RelayUdpSocket { outgoing, incoming }
+    }
+}
+
+#[async_trait]
+impl AsyncUdpSocket for RelayUdpSocket {
+    async fn connect(&self, _addr: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing
+            .send(buf.to_vec())
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let datagram = self
+            .incoming
+            .recv()
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        // truncated like a udp datagram
+        let n = datagram.len().min(buf.len());
+        buf[..n].copy_from_slice(&datagram[..n]);
+        Ok(n)
+    }
+
+    async fn recv_timeout(&self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        futures_lite::future::or(self.recv(buf), async {
+            async_io::Timer::after(timeout).await;
+            Err(io::ErrorKind::TimedOut.into())
+        })
+        .await
+    }
+}
+
+/// Create a NAT-PMP object tunnelling through a relay, see
+/// [`RelayUdpSocket`](struct.RelayUdpSocket.html).
+pub fn new_relay_natpmp(
+    outgoing: Sender<Vec<u8>>,
+    incoming: Receiver<Vec<u8>>,
+    gateway: Ipv4Addr,
+) -> NatpmpAsync<RelayUdpSocket> {
+    new_natpmp_async_with(RelayUdpSocket::new(outgoing, incoming), gateway)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::tests::mapping_response;
+    use crate::{decode_request, Protocol, RequestKind, Response};
+
+    #[test]
+    fn test_relay() {
+        let (outgoing, relay_rx) = async_channel::unbounded::<Vec<u8>>();
+        let (relay_tx, incoming) = async_channel::unbounded();
+        let relay = thread::spawn(move || {
+            // the first request is lost on the way
+            let mut received = vec![relay_rx.recv_blocking().unwrap()];
+            while let Ok(request) = relay_rx.recv_blocking() {
+                let response = match decode_request(&request).unwrap() {
+                    RequestKind::PublicAddress => vec![0, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 7],
+                    RequestKind::Mapping { .. } => mapping_response(&request, 8080, 60),
+                };
+                relay_tx.send_blocking(response).unwrap();
+                received.push(request);
+            }
+            received
+        });
+
+        let n = new_relay_natpmp(outgoing, incoming, Ipv4Addr::new(192, 168, 0, 1));
+        futures_lite::future::block_on(async {
+            n.send_public_address_request().await.unwrap();
+            match n.read_response_or_retry().await.unwrap() {
+                Response::Gateway(gr) => {
+                    assert_eq!(gr.public_address(), &Ipv4Addr::new(203, 0, 113, 7))
+                }
+                _ => panic!("Not a gateway response"),
+            }
+            n.send_port_mapping_request(Protocol::TCP, 8080, 8080, 60)
+                .await
+                .unwrap();
+            match n.read_response_or_retry().await.unwrap() {
+                Response::TCP(m) => assert_eq!(m.public_port(), 8080),
+                _ => panic!("Not a tcp response"),
+            }
+        });
+        drop(n);
+        // retransmitted after the loss
+        assert_eq!(relay.join().unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,215 @@
+use std::time::Duration;
+
+use crate::{Error, Result, NATPMP_MAX_ATTEMPS, NATPMP_MIN_WAIT};
+
+/// How requests are retransmitted while waiting for the gateway.
+///
+/// Attempt `k` waits `initial * multiplier^(k - 1)`, capped at `max_interval`, before the
+/// request is sent again. The gateway is given up on after `max_attempts` attempts.
+///
+/// # Examples
+/// ```
+/// use std::time::Duration;
+/// use natpmp::*;
+///
+/// # fn main() -> Result<()> {
+/// let profile = RetransmissionProfile::new(
+///     Duration::from_millis(500),
+///     1.5,
+///     5,
+///     Duration::from_secs(2),
+/// )?;
+/// assert_eq!(profile.delay(2), Duration::from_millis(750));
+/// assert_eq!(profile.delay(5), Duration::from_secs(2));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RetransmissionProfile {
+    initial: Duration,
+    multiplier: f32,
+    max_attempts: u32,
+    max_interval: Duration,
+}
+
+impl RetransmissionProfile {
+    /// Create a profile.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_INVALIDARGS`](enum.Error.html#variant.NATPMP_ERR_INVALIDARGS) if
+    ///   `initial` is zero, `multiplier` is below 1, `max_attempts` is zero or `max_interval`
+    ///   is shorter than `initial`
+    pub fn new(
+        initial: Duration,
+        multiplier: f32,
+        max_attempts: u32,
+        max_interval: Duration,
+    ) -> Result<RetransmissionProfile> {
+        if initial == Duration::from_millis(0)
+            || !(multiplier.is_finite() && multiplier >= 1.0)
+            || max_attempts == 0
+            || max_interval < initial
+        {
+            return Err(Error::NATPMP_ERR_INVALIDARGS);
+        }
+        Ok(RetransmissionProfile {
+            initial,
+            multiplier,
+            max_attempts,
+            max_interval,
+        })
+    }
+
+    /// Profile of rfc6886: 250ms doubling over 9 attempts, about 128s in total.
+    pub fn rfc_default() -> RetransmissionProfile {
+        RetransmissionProfile {
+            initial: Duration::from_millis(NATPMP_MIN_WAIT),
+            multiplier: 2.0,
+            max_attempts: NATPMP_MAX_ATTEMPS,
+            max_interval: Duration::from_millis(NATPMP_MIN_WAIT << (NATPMP_MAX_ATTEMPS - 1)),
+        }
+    }
+
+    /// Profile for links where the first reply may take longer than 250ms, e.g. satellite:
+    /// 1s doubling over 6 attempts, about 63s in total.
+    pub fn high_latency() -> RetransmissionProfile {
+        RetransmissionProfile {
+            initial: Duration::from_secs(1),
+            multiplier: 2.0,
+            max_attempts: 6,
+            max_interval: Duration::from_secs(32),
+        }
+    }
+
+    /// Profile giving up quickly on a silent gateway: 250ms doubling over 3 attempts, 1.75s
+    /// in total.
+    pub fn fast_fail() -> RetransmissionProfile {
+        RetransmissionProfile {
+            initial: Duration::from_millis(NATPMP_MIN_WAIT),
+            multiplier: 2.0,
+            max_attempts: 3,
+            max_interval: Duration::from_secs(1),
+        }
+    }
+
+    /// Timeout of the first attempt.
+    pub fn initial(&self) -> Duration {
+        self.initial
+    }
+
+    /// Growth of the timeout from one attempt to the next.
+    pub fn multiplier(&self) -> f32 {
+        self.multiplier
+    }
+
+    /// Number of attempts before giving up.
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Longest timeout of an attempt.
+    pub fn max_interval(&self) -> Duration {
+        self.max_interval
+    }
+
+    /// Timeout of `attempt`, counting from 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let factor = f64::from(self.multiplier).powi(exponent);
+        let delay = self.initial.as_secs_f64() * factor;
+        if delay >= self.max_interval.as_secs_f64() {
+            return self.max_interval;
+        }
+        Duration::from_secs_f64(delay)
+    }
+}
+
+impl Default for RetransmissionProfile {
+    fn default() -> RetransmissionProfile {
+        RetransmissionProfile::rfc_default()
+    }
+}
+
+/// Random spread of the retransmission timeouts of a client, see
+/// [`Natpmp::set_jitter`](struct.Natpmp.html#method.set_jitter).
+#[derive(Debug, Clone)]
+pub(crate) struct Jitter {
+    fraction: f32,
+    seed: Option<u64>,
+    rng: fastrand::Rng,
+}
+
+impl Jitter {
+    /// No jitter, with a randomly seeded source.
+    pub(crate) fn new() -> Jitter {
+        Jitter {
+            fraction: 0.0,
+            seed: None,
+            rng: fastrand::Rng::new(),
+        }
+    }
+
+    /// Spread timeouts by up to `±fraction` of their length.
+    ///
+    /// # Errors
+    /// * [`Error::NATPMP_ERR_INVALIDARGS`](enum.Error.html#variant.NATPMP_ERR_INVALIDARGS) if
+    ///   `fraction` is not within `0.0..=1.0`
+    pub(crate) fn set(&mut self, fraction: f32) -> Result<()> {
+        if !(0.0..=1.0).contains(&fraction) {
+            return Err(Error::NATPMP_ERR_INVALIDARGS);
+        }
+        self.fraction = fraction;
+        Ok(())
+    }
+
+    pub(crate) fn fraction(&self) -> f32 {
+        self.fraction
+    }
+
+    /// Seed given to [`set_seed`](#method.set_seed), `None` if seeded randomly.
+    pub(crate) fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    pub(crate) fn set_seed(&mut self, seed: u64) {
+        self.seed = Some(seed);
+        self.rng.seed(seed);
+    }
+
+    /// `delay` with jitter applied.
+    pub(crate) fn apply(&mut self, delay: Duration) -> Duration {
+        if self.fraction == 0.0 {
+            return delay;
+        }
+        let factor = 1.0 + self.fraction * (self.rng.f32() * 2.0 - 1.0);
+        delay.mul_f32(factor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile() {
+        let rfc = RetransmissionProfile::rfc_default();
+        let total: Duration = (1..=rfc.max_attempts()).map(|k| rfc.delay(k)).sum();
+        assert_eq!(total, Duration::from_millis(127_750));
+        assert_eq!(rfc.delay(100), rfc.max_interval());
+
+        let second = Duration::from_secs(1);
+        assert!(RetransmissionProfile::new(second, 2.0, 3, second).is_ok());
+        for (initial, multiplier, attempts, max) in [
+            (Duration::from_millis(0), 2.0, 3, second),
+            (second, 0.5, 3, second),
+            (second, f32::NAN, 3, second),
+            (second, 2.0, 0, second),
+            (second, 2.0, 3, Duration::from_millis(10)),
+        ] {
+            assert_eq!(
+                RetransmissionProfile::new(initial, multiplier, attempts, max),
+                Err(Error::NATPMP_ERR_INVALIDARGS)
+            );
+        }
+    }
+}